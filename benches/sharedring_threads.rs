@@ -0,0 +1,64 @@
+//! Sender and receiver in different threads, pinned to different cores when there are several,
+//! so that the cost of moving cache lines between them shows up in the numbers.
+use std::time::{Duration, Instant};
+use criterion::*;
+use shmem_ipc::sharedring::{Sender, Receiver};
+
+fn setup_one<T: zerocopy::AsBytes + Copy + zerocopy::FromBytes>(chunks: usize) -> (Sender<T>, Receiver<T>) {
+    let s: Sender<T> = Sender::new(chunks).unwrap();
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let r: Receiver<T> = Receiver::open(chunks, memfd, e, f).unwrap();
+    (s, r)
+}
+
+fn pin_to_cpu(cpu: usize) {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu % cpus, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+    }
+}
+
+fn bench_threads<M: measurement::Measurement<Value = Duration>>(c: &mut BenchmarkGroup<M>, chunk: usize) {
+    c.bench_with_input(BenchmarkId::new("Sharedring", chunk*8), &chunk, |b, &chunk| b.iter_custom(|iters| {
+        let (mut s, mut r) = setup_one::<u64>(4 * chunk);
+        let total = iters as usize * chunk;
+        let start = Instant::now();
+        let t = std::thread::spawn(move || {
+            pin_to_cpu(0);
+            let data = vec![1u64; chunk];
+            let mut sent = 0;
+            while sent < total {
+                s.block_until_writable().unwrap();
+                sent += s.send_from(&data[..std::cmp::min(chunk, total - sent)]).unwrap();
+            }
+        });
+        pin_to_cpu(1);
+        let mut buf = vec![0u64; chunk];
+        let mut received = 0;
+        while received < total {
+            r.block_until_readable().unwrap();
+            received += r.recv_into(&mut buf).unwrap();
+        }
+        let elapsed = start.elapsed();
+        t.join().unwrap();
+        assert_eq!(received, total);
+        elapsed
+    }));
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Sharedring across cores");
+    group.warm_up_time(Duration::from_millis(500));
+    group.sample_size(40);
+    group.measurement_time(Duration::from_millis(2500));
+    for chunk in [1, 16, 256, 4096] {
+        bench_threads(&mut group, chunk);
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
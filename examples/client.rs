@@ -4,18 +4,15 @@ use dbus::blocking::{Connection, Proxy};
 use std::error::Error;
 use std::thread::sleep;
 use shmem_ipc::sharedring::Sender;
+use shmem_ipc::sharedring::dbus::open_via_dbus;
 use std::time::Duration;
-use std::fs::File;
 
 fn main() -> Result<(), Box<dyn Error>> {
     // Setup a D-Bus connection and call the Setup method of the server.
     let c = Connection::new_session()?;
     let proxy = Proxy::new("com.example.shmemtest", "/shmemtest", Duration::from_millis(3000), &c);
-    let (capacity, memfd, empty_signal, full_signal): (u64, File, File, File) =
-        proxy.method_call("com.example.shmemtest", "Setup", ())?;
-
     // Setup the ringbuffer.
-    let mut r = Sender::open(capacity as usize, memfd, empty_signal, full_signal)?;
+    let mut r: Sender<f64> = open_via_dbus(&proxy, "com.example.shmemtest", "Setup")?;
     let mut items = 100000;
     loop {
         let item = 1.0f64 / (items as f64);
@@ -25,7 +22,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             // Rust slice, so we write the data through the raw pointer directly.
             if items < count { count = items };
             for i in 0..count {
-                *p.offset(i as isize) = item;
+                *p.add(i) = item;
             }
             println!("Sending {} items of {}, in total {}", count, item, (count as f64) * item);
             count
@@ -9,12 +9,11 @@ use dbus::Message;
 use std::sync::Mutex;
 use std::sync::Arc;
 use std::thread;
-use dbus::MethodErr;
 use dbus::blocking::Connection;
-use std::fs::File;
 use dbus_crossroads::{Crossroads};
 use std::error::Error;
 use shmem_ipc::sharedring::Receiver;
+use shmem_ipc::sharedring::dbus::receiver_method;
 
 const CAPACITY: usize = 500000;
 
@@ -24,14 +23,10 @@ struct State {
 }
 
 impl State {
-    fn add_receiver(&mut self) -> Result<(u64, File, File, File), Box<dyn Error>> {
-        // Create a receiver in shared memory.
-        let mut r = Receiver::new(CAPACITY as usize)?;
-        let m = r.memfd().as_file().try_clone()?;
-        let e = r.empty_signal().try_clone()?;
-        let f = r.full_signal().try_clone()?;
+    fn add_receiver(&mut self, mut r: Receiver<f64>) {
         // In this example, we spawn a thread for every ringbuffer.
-        // More complex real-world scenarios might multiplex using non-block frameworks,
+        // More complex real-world scenarios might multiplex using `sharedring::Selector`
+        // or non-block frameworks,
         // as well as having a mechanism to detect when a client is gone.
         let sum = self.sum.clone();
         thread::spawn(move || {
@@ -43,14 +38,13 @@ impl State {
                     // and the untrusted process restrictions, we cannot convert them into a
                     // Rust slice, so we read the data from the raw pointer directly.
                     for i in 0..count {
-                        s += *ptr.offset(i as isize);
+                        s += *ptr.add(i);
                     }
                     *sum.lock().unwrap() += s;
                     count
                 }).unwrap();
             }
         });
-        Ok((CAPACITY as u64, m, e, f))
     }
 }
 
@@ -59,11 +53,10 @@ fn main() -> Result<(), Box<dyn Error>> {
     c.request_name("com.example.shmemtest", false, true, false)?;
     let mut cr = Crossroads::new();
     let iface_token = cr.register("com.example.shmemtest", |b| {
-        b.method("Setup", (), ("capacity", "memfd", "empty_signal", "full_signal"), |_, state: &mut State, _: ()| {
-            state.add_receiver().map_err(|e| {
-                println!("{}, {:?}", e, e.source());
-                MethodErr::failed("failed to setup shared memory")
-            })
+        // Creates a receiver in shared memory for every call, and returns it to the client.
+        receiver_method(b, "Setup", CAPACITY, |_, state: &mut State, r| {
+            state.add_receiver(r);
+            Ok(())
         });
         b.signal::<(f64,), _>("Sum", ("sum",));
     });
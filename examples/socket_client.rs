@@ -0,0 +1,25 @@
+//! Sends a lot of f64 values over shared memory to the socket_server example every second.
+
+use shmem_ipc::sharedring::server::{connect, Connection};
+use std::thread::sleep;
+use std::time::Duration;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("shmemtest.sock");
+    let mut conn: Connection<u8, f64> = connect(&path)?;
+    let mut items = 100000;
+    loop {
+        let item = 1.0f64 / (items as f64);
+        conn.sender().send_raw(|p: *mut f64, mut count| unsafe {
+            // Write through the raw pointer, see the client example.
+            if items < count { count = items };
+            for i in 0..count {
+                *p.add(i) = item;
+            }
+            println!("Sending {} items of {}, in total {}", count, item, (count as f64) * item);
+            count
+        })?;
+        items += 100000;
+        sleep(Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,42 @@
+//! Like the server example, but clients connect to a unix socket instead of calling a D-Bus method.
+//!
+//! Every client gets its own thread, which ends when the client disconnects.
+
+use shmem_ipc::sharedring::server::{Config, Listener};
+use shmem_ipc::Error;
+use std::thread;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("shmemtest.sock");
+    let _ = std::fs::remove_file(&path);
+    // The server receives f64 items, and does not send anything back.
+    let mut listener: Listener<f64, u8> = Listener::bind(&path, Config::default())?;
+    println!("Listening on {}", path.display());
+    loop {
+        let mut conn = match listener.accept() {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Client setup failed: {}", e);
+                continue;
+            }
+        };
+        thread::spawn(move || loop {
+            match conn.receiver().block_until_readable() {
+                Err(Error::Cancelled) => {
+                    println!("Client disconnected");
+                    return;
+                }
+                r => r.unwrap(),
+            };
+            let mut sum = 0.0f64;
+            conn.receiver().receive_raw(|ptr: *const f64, count| unsafe {
+                // Read through the raw pointer, see the server example.
+                for i in 0..count {
+                    sum += *ptr.add(i);
+                }
+                count
+            }).unwrap();
+            println!("Sum: {}", sum);
+        });
+    }
+}
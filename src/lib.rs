@@ -18,7 +18,10 @@ pub mod ringbuf;
 pub mod sharedring;
 
 /// Enumeration of errors possible in this library
+///
+/// Which variants exist depends on the enabled features, so matches need a wildcard arm.
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("Memfd errors {0:?}")]
     Memfd(#[from] mem::mfd::Error),
@@ -26,4 +29,13 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("Ringbuffer errors {0:?}")]
     Ringbuf(#[from] ringbuf::Error),
+    #[error("Timed out waiting for the other side")]
+    Timeout,
+    #[error("Wait cancelled through an Interrupter")]
+    Cancelled,
+    #[error("Handshake failed: {0}")]
+    Handshake(&'static str),
+    #[cfg(feature = "dbus")]
+    #[error("D-Bus errors {0:?}")]
+    Dbus(#[from] dbus::Error),
 }
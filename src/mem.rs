@@ -25,9 +25,9 @@ fn verify_seal(memfd: &mfd::Memfd, seal: mfd::FileSeal) -> Result<(), Error> {
 /// Creates a memory map of a memfd. The memfd is sealed to be read only.
 pub fn read_memfd(memfd: &mfd::Memfd) -> Result<mmap::Mmap, Error> {
     // The file can be truncated; no safe memory mapping.
-    verify_seal(&memfd, mfd::FileSeal::SealShrink)?;
+    verify_seal(memfd, mfd::FileSeal::SealShrink)?;
     // The file can be written to; no safe references.
-    verify_seal(&memfd, mfd::FileSeal::SealWrite)?;
+    verify_seal(memfd, mfd::FileSeal::SealWrite)?;
 
     let r = unsafe { mmap::MmapOptions::new().map_copy_read_only(memfd.as_file()) }?;
     Ok(r)
@@ -36,7 +36,7 @@ pub fn read_memfd(memfd: &mfd::Memfd) -> Result<mmap::Mmap, Error> {
 /// Creates a raw memory map of a memfd, suitable for IPC. It must be writable.
 pub fn raw_memfd(memfd: &mfd::Memfd, len: usize) -> Result<mmap::MmapRaw, Error> {
     // The file can be truncated; no safe memory mapping.
-    verify_seal(&memfd, mfd::FileSeal::SealShrink)?;
+    verify_seal(memfd, mfd::FileSeal::SealShrink)?;
 
     // If the file has been sealed as read-only, the below will fail.
     // If the file later is trying to be sealed as read-only, that call will fail and
@@ -45,6 +45,110 @@ pub fn raw_memfd(memfd: &mfd::Memfd, len: usize) -> Result<mmap::MmapRaw, Error>
     Ok(mmap::MmapOptions::new().len(len).map_raw(memfd.as_file())?)
 }
 
+/// Returns the size of a memory page.
+pub fn page_size() -> usize { unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize } }
+
+/// A memory map where the data part of a memfd is mapped twice, back to back.
+///
+/// The memory layout is a header, followed by the data, followed by the data again.
+/// Writing to the data, or to its mirror, changes both.
+pub struct MirroredMmap {
+    ptr: *mut u8,
+    header_len: usize,
+    data_len: usize,
+}
+
+unsafe impl Send for MirroredMmap {}
+unsafe impl Sync for MirroredMmap {}
+
+impl MirroredMmap {
+    /// Pointer to the start of the header.
+    pub fn as_mut_ptr(&self) -> *mut u8 { self.ptr }
+    /// Total length of the memory map, i e, the header plus two times the data.
+    pub fn len(&self) -> usize { self.header_len + 2 * self.data_len }
+    /// Returns true if the memory map has zero length.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+    /// Length of the header.
+    pub fn header_len(&self) -> usize { self.header_len }
+    /// Length of the data, not counting the mirror.
+    pub fn data_len(&self) -> usize { self.data_len }
+    /// Locks the memory map into RAM.
+    pub fn lock(&self) -> Result<(), std::io::Error> {
+        if unsafe { libc::mlock(self.ptr as *const _, self.len()) } < 0 {
+            Err(std::io::Error::last_os_error())?
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MirroredMmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut _, self.len()) };
+    }
+}
+
+/// Creates a raw memory map of a memfd, where the data is mapped twice. It must be writable.
+///
+/// The header starts at offset zero of the memfd and is directly followed by the data.
+/// Both lengths must be a multiple of the page size.
+pub fn raw_mirrored_memfd(memfd: &mfd::Memfd, header_len: usize, data_len: usize) -> Result<MirroredMmap, Error> {
+    use std::os::unix::io::AsRawFd;
+    // The file can be truncated; no safe memory mapping.
+    verify_seal(memfd, mfd::FileSeal::SealShrink)?;
+    let ps = page_size();
+    if header_len % ps != 0 || data_len % ps != 0 || data_len == 0 {
+        Err(crate::ringbuf::Error::BufUnaligned)?
+    }
+    if memfd.as_file().metadata()?.len() < (header_len + data_len) as u64 {
+        Err(crate::ringbuf::Error::BufTooSmall)?
+    }
+    let total = header_len + 2 * data_len;
+    let fd = memfd.as_raw_fd();
+    unsafe {
+        // Reserve the address space first, then map the memfd on top of it.
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+        let ptr = libc::mmap(std::ptr::null_mut(), total, libc::PROT_NONE, flags, -1, 0);
+        if ptr == libc::MAP_FAILED {
+            Err(std::io::Error::last_os_error())?
+        }
+        let r = MirroredMmap { ptr: ptr as *mut u8, header_len, data_len };
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_SHARED | libc::MAP_FIXED;
+        if libc::mmap(ptr, header_len + data_len, prot, flags, fd, 0) == libc::MAP_FAILED {
+            Err(std::io::Error::last_os_error())?
+        }
+        let mirror = r.ptr.add(header_len + data_len) as *mut _;
+        if libc::mmap(mirror, data_len, prot, flags, fd, header_len as libc::off_t) == libc::MAP_FAILED {
+            Err(std::io::Error::last_os_error())?
+        }
+        Ok(r)
+    }
+}
+
+/// Creates a read-only memory map of a memfd, without sealing it.
+///
+/// Unlike `read_memfd`, the mapping is shared, so the contents may change at any time if another
+/// process has a writable mapping of the memfd. Therefore you must not create references to the data.
+pub fn read_raw_memfd(memfd: &mfd::Memfd, len: usize) -> Result<mmap::Mmap, Error> {
+    // The file can be truncated; no safe memory mapping.
+    verify_seal(memfd, mfd::FileSeal::SealShrink)?;
+
+    Ok(unsafe { mmap::MmapOptions::new().len(len).map(memfd.as_file())? })
+}
+
+/// Seals the memfd so that no new writable mappings can be created, while existing ones keep working.
+///
+/// Use this after creating your own writable mapping, to make sure other processes can only
+/// create read-only mappings. Supports linux version 5.1+ only.
+pub fn seal_future_write(memfd: &mfd::Memfd) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+    let r = unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, libc::F_SEAL_FUTURE_WRITE) };
+    if r < 0 {
+        Err(std::io::Error::last_os_error())?
+    }
+    Ok(())
+}
+
 /// Creates a shared memory area that can be written once and read many times.
 ///
 /// The memfd is created, memory mapped and the closure can fill in the data.
@@ -121,6 +225,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn mirrored() -> Result<(), Error> {
+        let opts = mfd::MemfdOptions::default().allow_sealing(true);
+        let memfd = opts.create("test-mirrored")?;
+        let ps = page_size();
+        memfd.as_file().set_len(3 * ps as u64)?;
+        assert!(raw_mirrored_memfd(&memfd, ps, 4 * ps).is_err());
+        let m = raw_mirrored_memfd(&memfd, ps, 2 * ps)?;
+        assert_eq!(m.len(), 5 * ps);
+        unsafe {
+            let p = m.as_mut_ptr();
+            *p.add(ps + 5) = 7;
+            assert_eq!(*p.add(3 * ps + 5), 7);
+            *p.add(5 * ps - 1) = 9;
+            assert_eq!(*p.add(3 * ps - 1), 9);
+            assert_eq!(*p.add(ps - 1), 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn future_write_sealed() -> Result<(), Error> {
+        let opts = mfd::MemfdOptions::default().allow_sealing(true);
+        let memfd = opts.create("test-future-write")?;
+        memfd.as_file().set_len(16384)?;
+        let mmap_raw = raw_memfd(&memfd, 16384)?;
+        seal_future_write(&memfd)?;
+        // No more writable mappings, but read-only ones are fine.
+        assert!(raw_memfd(&memfd, 16384).is_err());
+        let mmap_ro = read_raw_memfd(&memfd, 16384)?;
+        unsafe { *mmap_raw.as_mut_ptr().add(5) = 7 };
+        assert_eq!(mmap_ro[5], 7);
+        Ok(())
+    }
+
     #[test]
     fn write_then_read() -> Result<(), Error> {
         let m = write_once(4096, "write_then_read_test", |x| {
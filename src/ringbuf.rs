@@ -3,9 +3,11 @@
 //! i e, they are Send but not Clone.
 
 use std::mem::size_of;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
 use std::{cmp, ptr};
 
+pub mod broadcast;
+
 /// Enumeration of errors possible in this library
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -21,13 +23,36 @@ pub enum Error {
     CallbackReadTooMuch,
     #[error("Callback wrote more items than available in the buffer")]
     CallbackWroteTooMuch,
+    #[error("Message header corrupt or message length out of range")]
+    MessageCorrupt,
+    #[error("Buffer header has the wrong magic number, not a ringbuffer or uninitialized")]
+    HeaderMagic,
+    #[error("Buffer header has an unsupported layout version")]
+    HeaderVersion,
+    #[error("Item size or alignment differs from what the buffer was set up with")]
+    ItemLayout,
+    #[error("Capacity differs from what the buffer was set up with")]
+    CapacityMismatch,
+    #[error("Buffer was set up with a different mapping (mirrored or not)")]
+    MirroredMismatch,
+    #[error("Item type fingerprint differs from what the buffer was set up with")]
+    FingerprintMismatch,
 }
 
 #[derive(Copy, Clone)]
 struct Buf<T> {
+    header: *mut Header,
     data: *mut T,
-    count_ptr: *const AtomicUsize,
+    head_ptr: *const AtomicUsize,
+    waste_ptr: *const AtomicUsize,
+    write_mark_ptr: *const AtomicUsize,
+    receiver_waiting_ptr: *const AtomicUsize,
+    tail_ptr: *const AtomicUsize,
+    read_mark_ptr: *const AtomicUsize,
+    sender_waiting_ptr: *const AtomicUsize,
     length: usize,
+    /// The data is mapped twice, back to back, so "length" items can always be accessed contiguously.
+    mirrored: bool,
 }
 
 unsafe impl<T> Send for Buf<T> {}
@@ -35,12 +60,18 @@ unsafe impl<T> Sync for Buf<T> {}
 
 pub struct Sender<T> {
     buf: Buf<T>,
-    index: usize,
+    /// Write position, always the same as the one in the buffer.
+    head: usize,
+    /// Read position, as last loaded from the buffer. The real one might be further ahead.
+    tail: usize,
 }
 
 pub struct Receiver<T> {
     buf: Buf<T>,
-    index: usize,
+    /// Read position, always the same as the one in the buffer.
+    tail: usize,
+    /// Write position, as last loaded from the buffer. The real one might be further ahead.
+    head: usize,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -53,8 +84,37 @@ pub struct Status {
 
 const CACHE_LINE_SIZE: usize = 64;
 
+/// The first cache line describes the buffer. The second holds the write position, the waste,
+/// the write watermark, the receiver's waiting flag, the read position, the read watermark and
+/// the sender's waiting flag. The third holds the futex words, see `futex_words`.
+///
+/// The positions go from 0 to 2 * capacity, so that a full buffer can be told from an empty one.
+const HEADER_SIZE: usize = 3 * CACHE_LINE_SIZE;
+
+const FUTEX_OFFSET: usize = 2 * CACHE_LINE_SIZE;
+
+const HEADER_MAGIC: u32 = u32::from_be_bytes(*b"SHMR");
+const HEADER_VERSION: u32 = 8;
+const HEADER_FLAG_MIRRORED: u32 = 1;
+/// The fingerprint must be the `type_fingerprint` of T, see `Sender::require_fingerprint`.
+const HEADER_FLAG_FINGERPRINT: u32 = 2;
+
+/// Written once by the side setting up the buffer, and verified by the side attaching to it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct Header {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    item_align: u32,
+    item_size: u64,
+    /// Optional, zero if not used. Only verified if HEADER_FLAG_FINGERPRINT is set.
+    fingerprint: u64,
+    capacity: u64,
+}
+
 /// Use this utility function to figure out how big buffer you need to allocate.
-pub fn channel_bufsize<T>(capacity: usize) -> usize { capacity * size_of::<T>() + CACHE_LINE_SIZE }
+pub fn channel_bufsize<T>(capacity: usize) -> usize { capacity * size_of::<T>() + HEADER_SIZE }
 
 /// Initializes a ring buffer.
 ///
@@ -62,17 +122,136 @@ pub fn channel_bufsize<T>(capacity: usize) -> usize { capacity * size_of::<T>()
 ///
 /// In case the buffer is too small or too big.
 pub fn channel<T: zerocopy::AsBytes + zerocopy::FromBytes + Copy>(buffer: &mut [u8]) -> (Sender<T>, Receiver<T>) {
-    let b = unsafe { Buf::attach(buffer.as_mut_ptr(), buffer.len(), true).unwrap() };
-    (Sender { buf: b, index: 0 }, Receiver { buf: b, index: 0 })
+    let b = unsafe { Buf::attach(buffer.as_mut_ptr(), buffer.len(), Some(type_fingerprint::<T>())).unwrap() };
+    (Sender { buf: b, head: 0, tail: 0 }, Receiver { buf: b, tail: 0, head: 0 })
+}
+
+/// Sets up a ringbuf at the location, for items of type T.
+///
+/// This writes the header that `Sender::attach` and `Receiver::attach` verify, so it must be
+/// called by the side that creates the buffer, before the other side attaches to it.
+/// The fingerprint is stored in the header as is; use zero if you don't need one.
+///
+/// # Safety
+///
+/// You must ensure that "data" points to a readable and writable memory area of "length" bytes.
+pub unsafe fn init<T>(data: *mut u8, length: usize, fingerprint: u64) -> Result<(), Error> {
+    Buf::<T>::attach(data, length, Some(fingerprint))?;
+    Ok(())
+}
+
+/// Like `init`, but for a ringbuf with the data mapped twice.
+///
+/// # Safety
+///
+/// See `Sender::attach_mirrored`.
+pub unsafe fn init_mirrored<T>(data: *mut u8, header_size: usize, capacity: usize, fingerprint: u64) -> Result<(), Error> {
+    Buf::<T>::attach_header(data, header_size, capacity, true, Some(fingerprint))?;
+    Ok(())
+}
+
+/// Returns the futex words in the header of a ringbuf at the location.
+///
+/// These are for the sides to wake each other up without eventfds: the receiver waits on
+/// the first one when the buffer is empty, and the sender waits on the second one when the
+/// buffer is full. The ringbuf itself never looks at them.
+///
+/// # Safety
+///
+/// "data" must point to a ringbuf set up with `init` or `init_mirrored`, and the returned
+/// pointers are only valid for as long as the ringbuf is.
+pub(crate) unsafe fn futex_words(data: *mut u8) -> (*const AtomicU32, *const AtomicU32) {
+    let p = data.add(FUTEX_OFFSET) as *const AtomicU32;
+    (p, p.add(1))
+}
+
+/// A fingerprint of the type name of T, for storing in the header of a buffer.
+///
+/// Type names are not guaranteed to be stable between compiler versions, so this is only
+/// useful if both sides are built with the same compiler.
+pub fn type_fingerprint<T>() -> u64 {
+    // FNV-1a
+    std::any::type_name::<T>().bytes().fold(0xcbf29ce484222325, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
 }
 
 impl<T> Buf<T> {
     #[inline]
-    fn count(&self) -> &AtomicUsize { unsafe { &*self.count_ptr } }
+    fn head(&self) -> &AtomicUsize { unsafe { &*self.head_ptr } }
+
+    #[inline]
+    fn tail(&self) -> &AtomicUsize { unsafe { &*self.tail_ptr } }
+
+    /// Position of the first item at the end of the buffer that was skipped by
+    /// `Sender::send_contiguous`, plus one, or zero if no items were skipped.
+    ///
+    /// Only the sender writes to it, so the receiver's state is just the read position.
+    /// The sender clears it once the receiver is known to be past it.
+    #[inline]
+    fn waste(&self) -> &AtomicUsize { unsafe { &*self.waste_ptr } }
+
+    /// Number of free items the sender wants before being signalled, written by the sender.
+    #[inline]
+    fn write_mark(&self) -> &AtomicUsize { unsafe { &*self.write_mark_ptr } }
+
+    /// Number of available items the receiver wants before being signalled, written by the receiver.
+    #[inline]
+    fn read_mark(&self) -> &AtomicUsize { unsafe { &*self.read_mark_ptr } }
+
+    /// Non-zero if the sender might be waiting for a signal, written by the sender and
+    /// checked by the receiver.
+    #[inline]
+    fn sender_waiting(&self) -> &AtomicUsize { unsafe { &*self.sender_waiting_ptr } }
+
+    /// Non-zero if the receiver might be waiting for a signal, written by the receiver and
+    /// checked by the sender.
+    #[inline]
+    fn receiver_waiting(&self) -> &AtomicUsize { unsafe { &*self.receiver_waiting_ptr } }
+
+    /// Loads a watermark, which might have been written by the other side. Zero means one,
+    /// and anything above the capacity means the capacity.
+    #[inline]
+    fn load_mark(&self, mark: &AtomicUsize) -> usize { cmp::min(cmp::max(mark.load(Ordering::Acquire), 1), self.length) }
+
+    /// Returns the number of items from "tail" to the wasted items, and the number of wasted
+    /// items, if the wasted items are within the "cb" items following "tail".
+    fn waste_at(&self, tail: usize, cb: usize) -> Result<Option<(usize, usize)>, Error> {
+        let m = self.waste().load(Ordering::Acquire);
+        if self.mirrored || m == 0 {
+            return Ok(None);
+        }
+        if m > 2 * self.length || self.offset(m - 1) == 0 {
+            Err(Error::BufCorrupt)?
+        }
+        let (pos, w) = (m - 1, self.length - self.offset(m - 1));
+        let d = if pos >= tail { pos - tail } else { pos + 2 * self.length - tail };
+        Ok(if d + w <= cb { Some((d, w)) } else { None })
+    }
+
+    /// Loads a position written by the other side, which must not be trusted.
+    #[inline]
+    fn load_pos(&self, pos: &AtomicUsize) -> Result<usize, Error> {
+        let x = pos.load(Ordering::Acquire);
+        if x >= 2 * self.length {
+            Err(Error::BufCorrupt)
+        } else {
+            Ok(x)
+        }
+    }
 
     #[inline]
-    fn load_count(&self) -> Result<usize, Error> {
-        let x = self.count().load(Ordering::Acquire);
+    fn advance(&self, pos: usize, n: usize) -> usize {
+        let x = pos + n;
+        if x >= 2 * self.length {
+            x - 2 * self.length
+        } else {
+            x
+        }
+    }
+
+    /// Number of items between the read position and the write position.
+    #[inline]
+    fn used(&self, tail: usize, head: usize) -> Result<usize, Error> {
+        let x = if head >= tail { head - tail } else { head + 2 * self.length - tail };
         if x > self.length {
             Err(Error::BufCorrupt)
         } else {
@@ -80,44 +259,168 @@ impl<T> Buf<T> {
         }
     }
 
-    unsafe fn attach(data: *mut u8, length: usize, init: bool) -> Result<Self, Error> {
+    /// Number of items between the read position and the write position that can be read,
+    /// i e, without the wasted items.
+    fn readable(&self, tail: usize, head: usize) -> Result<usize, Error> {
+        let used = self.used(tail, head)?;
+        Ok(used - self.waste_at(tail, used)?.map_or(0, |(_, w)| w))
+    }
+
+    /// Index into the data of the item at a position.
+    #[inline]
+    fn offset(&self, pos: usize) -> usize {
+        if pos >= self.length {
+            pos - self.length
+        } else {
+            pos
+        }
+    }
+
+    #[inline]
+    fn fingerprint(&self) -> u64 { unsafe { ptr::read_volatile(ptr::addr_of!((*self.header).fingerprint)) } }
+
+    fn require_fingerprint(&self) {
+        let fingerprint = type_fingerprint::<T>();
+        unsafe {
+            ptr::write_volatile(ptr::addr_of_mut!((*self.header).fingerprint), fingerprint);
+            let flags = ptr::addr_of_mut!((*self.header).flags);
+            ptr::write_volatile(flags, ptr::read_volatile(flags) | HEADER_FLAG_FINGERPRINT);
+        }
+    }
+
+    /// Initializes the buffer if "init" is set, otherwise verifies that the header matches.
+    unsafe fn attach(data: *mut u8, length: usize, init: Option<u64>) -> Result<Self, Error> {
+        if length < HEADER_SIZE + size_of::<T>() {
+            Err(Error::BufTooSmall)?
+        }
+        Self::attach_header(data, HEADER_SIZE, (length - HEADER_SIZE) / size_of::<T>(), false, init)
+    }
+
+    unsafe fn attach_header(data: *mut u8, header_size: usize, capacity: usize, mirrored: bool, init: Option<u64>) -> Result<Self, Error> {
         use Error::*;
-        if length < CACHE_LINE_SIZE + size_of::<T>() {
+        if header_size < HEADER_SIZE || capacity == 0 {
             Err(BufTooSmall)?
         }
-        if length >= isize::MAX as usize {
+        let bytes = capacity.checked_mul(2 * size_of::<T>()).and_then(|x| x.checked_add(header_size));
+        if bytes.map_or(true, |x| x >= isize::MAX as usize) {
             Err(BufTooBig)?
         }
         let r = Self {
-            count_ptr: data as *mut _ as *const AtomicUsize,
-            data: data.offset(CACHE_LINE_SIZE as isize) as _,
-            length: (length - CACHE_LINE_SIZE) / size_of::<T>(),
+            header: data as *mut Header,
+            head_ptr: data.add(CACHE_LINE_SIZE) as *const AtomicUsize,
+            waste_ptr: (data.add(CACHE_LINE_SIZE) as *const AtomicUsize).add(1),
+            write_mark_ptr: (data.add(CACHE_LINE_SIZE) as *const AtomicUsize).add(2),
+            receiver_waiting_ptr: (data.add(CACHE_LINE_SIZE) as *const AtomicUsize).add(3),
+            tail_ptr: (data.add(CACHE_LINE_SIZE) as *const AtomicUsize).add(4),
+            read_mark_ptr: (data.add(CACHE_LINE_SIZE) as *const AtomicUsize).add(5),
+            sender_waiting_ptr: (data.add(CACHE_LINE_SIZE) as *const AtomicUsize).add(6),
+            data: data.add(header_size) as _,
+            length: capacity,
+            mirrored,
         };
-        if (r.count_ptr as usize) % std::mem::align_of::<AtomicUsize>() != 0 {
+        if (data as usize) % std::mem::align_of::<Header>() != 0 {
             Err(BufUnaligned)?
         }
         if (r.data as usize) % std::mem::align_of::<T>() != 0 {
             Err(BufUnaligned)?
         }
-        if init {
-            r.count().store(0, Ordering::Release);
-        } else {
-            r.load_count()?;
+        let expected = Header {
+            magic: HEADER_MAGIC,
+            version: HEADER_VERSION,
+            flags: if mirrored { HEADER_FLAG_MIRRORED } else { 0 },
+            item_align: std::mem::align_of::<T>() as u32,
+            item_size: size_of::<T>() as u64,
+            fingerprint: init.unwrap_or(0),
+            capacity: capacity as u64,
+        };
+        if init.is_some() {
+            ptr::write_volatile(r.header, expected);
+            r.waste().store(0, Ordering::Release);
+            r.head().store(0, Ordering::Release);
+            r.tail().store(0, Ordering::Release);
+            r.write_mark().store(0, Ordering::Release);
+            r.read_mark().store(0, Ordering::Release);
+            r.sender_waiting().store(1, Ordering::Release);
+            r.receiver_waiting().store(1, Ordering::Release);
+            let (e, f) = futex_words(data);
+            (*e).store(0, Ordering::Release);
+            (*f).store(0, Ordering::Release);
+            return Ok(r);
+        }
+        let h = ptr::read_volatile(r.header);
+        if h.magic != expected.magic {
+            Err(HeaderMagic)?
+        }
+        if h.version != expected.version {
+            Err(HeaderVersion)?
+        }
+        if h.item_size != expected.item_size || h.item_align != expected.item_align {
+            Err(ItemLayout)?
+        }
+        if h.flags & !(HEADER_FLAG_MIRRORED | HEADER_FLAG_FINGERPRINT) != 0 {
+            Err(HeaderVersion)?
+        }
+        if h.flags & HEADER_FLAG_MIRRORED != expected.flags {
+            Err(MirroredMismatch)?
+        }
+        if h.capacity != expected.capacity {
+            Err(CapacityMismatch)?
+        }
+        if h.flags & HEADER_FLAG_FINGERPRINT != 0 && h.fingerprint != type_fingerprint::<T>() {
+            Err(FingerprintMismatch)?
         }
         Ok(r)
     }
+
+    /// Returns the read and write positions, as currently stored in the buffer.
+    fn load_positions(&self) -> Result<(usize, usize), Error> {
+        let (tail, head) = (self.load_pos(self.tail())?, self.load_pos(self.head())?);
+        self.used(tail, head)?;
+        Ok((tail, head))
+    }
+
+    /// The wasted items are only valid if they are between the read and write positions.
+    /// Anything else is left from a sender that went away between writing the waste and
+    /// the write position.
+    fn clear_stale_waste(&self, tail: usize, head: usize) -> Result<(), Error> {
+        if self.waste_at(tail, self.used(tail, head)?)?.is_none() {
+            self.waste().store(0, Ordering::Release);
+        }
+        Ok(())
+    }
 }
 
 impl<T: zerocopy::AsBytes + Copy> Sender<T> {
     /// Assume a ringbuf is set up at the location.
     ///
-    /// A buffer where the first 64 bytes are zero is okay.
+    /// The buffer must have been set up with `init`, for the same item type and length.
+    /// Sending continues from the write position stored in the buffer, so if a previous sender
+    /// went away, this one takes over where it stopped.
     ///
     /// # Safety
     ///
     /// You must ensure that "data" points to a readable and writable memory area of "length" bytes.
-    pub unsafe fn attach(data: *mut u8, length: usize) -> Result<Self, Error> {
-        Ok(Self { buf: Buf::attach(data, length, false)?, index: 0 })
+    pub unsafe fn attach(data: *mut u8, length: usize) -> Result<Self, Error> { Self::resume(Buf::attach(data, length, None)?) }
+
+    /// Assume a ringbuf is set up at the location, with the data mapped twice.
+    ///
+    /// The header is at "data" and the items start at "data + header_size". Since the items
+    /// are mirrored, all available items can always be accessed contiguously.
+    ///
+    /// # Safety
+    ///
+    /// You must ensure that "data" points to a readable and writable memory area of
+    /// "header_size" bytes, followed by "capacity" items, followed by a mirror of these items.
+    pub unsafe fn attach_mirrored(data: *mut u8, header_size: usize, capacity: usize) -> Result<Self, Error> {
+        Self::resume(Buf::attach_header(data, header_size, capacity, true, None)?)
+    }
+
+    fn resume(buf: Buf<T>) -> Result<Self, Error> {
+        let (tail, head) = buf.load_positions()?;
+        buf.clear_stale_waste(tail, head)?;
+        // We don't know how the previous sender waited.
+        buf.sender_waiting().store(1, Ordering::SeqCst);
+        Ok(Self { buf, head, tail })
     }
 
     /// Lowest level "send" function
@@ -131,55 +434,194 @@ impl<T: zerocopy::AsBytes + Copy> Sender<T> {
     /// Since this is a ringbuffer, there might be more items to write even if you
     /// completely fill up during the closure.
     pub fn send<F: FnOnce(*mut T, usize) -> usize>(&mut self, f: F) -> Result<Status, Error> {
-        let cb = self.buf.load_count()?;
-        let l = self.buf.length;
+        let guard = self.reserve(usize::MAX)?;
+        let n = if guard.is_empty() { 0 } else { f(guard.as_mut_ptr(), guard.len()) };
+        guard.commit(n)
+    }
 
-        let n = {
-            let end = self.index + cmp::min(l - self.index, l - cb);
-            let slice_start = unsafe { self.buf.data.offset(self.index as isize) };
-            let slice_len = end - self.index;
+    /// Like "send", but the closure is only called if at least "min" items can be written
+    /// contiguously.
+    ///
+    /// If there is not enough room before the end of the buffer, but enough room at the start,
+    /// the rest of the buffer is wasted (until the receiver has passed it) and the closure gets
+    /// a pointer to the start of the buffer.
+    pub fn send_contiguous<F: FnOnce(*mut T, usize) -> usize>(&mut self, min: usize, f: F) -> Result<Status, Error> {
+        let guard = self.reserve_contiguous(min)?;
+        let n = if guard.is_empty() { 0 } else { f(guard.as_mut_ptr(), guard.len()) };
+        guard.commit(n)
+    }
 
-            let n = if slice_len == 0 { 0 } else { f(slice_start, slice_len) };
-            if n > slice_len {
-                Err(Error::CallbackWroteTooMuch)?
-            }
-            assert!(n <= slice_len);
-            n
+    /// Reserves up to "max" items for writing.
+    ///
+    /// The returned guard gives access to the items that can be written contiguously, which
+    /// might be zero items if the buffer is full. Nothing is sent until the guard is committed.
+    pub fn reserve(&mut self, max: usize) -> Result<WriteGuard<'_, T>, Error> {
+        let start = self.buf.offset(self.head);
+        let room = if self.buf.mirrored { self.buf.length } else { self.buf.length - start };
+        let len = cmp::min(cmp::min(room, max), self.free(cmp::min(room, max))?);
+        Ok(WriteGuard { start, len, waste: 0, sender: self })
+    }
+
+    /// Like "reserve", but reserves at least "min" contiguous items, or nothing at all.
+    ///
+    /// See "send_contiguous" for how the end of the buffer is handled.
+    pub fn reserve_contiguous(&mut self, min: usize) -> Result<WriteGuard<'_, T>, Error> {
+        let free = self.free(min)?;
+        let index = self.buf.offset(self.head);
+        let tail = if self.buf.mirrored { self.buf.length } else { self.buf.length - index };
+        let (start, waste, len) = if cmp::min(tail, free) >= min {
+            (index, 0, cmp::min(tail, free))
+        } else if tail <= free && free - tail >= min {
+            (0, tail, free - tail)
+        } else {
+            (index, 0, 0)
         };
+        Ok(WriteGuard { start, len, waste, sender: self })
+    }
 
-        let c = self.buf.count().fetch_add(n, Ordering::AcqRel);
-        self.index = (self.index + n) % l;
-        // dbg!("Send: cb = {}, c = {}, l = {}, n = {}", cb, c, l, n);
-        Ok(Status { remaining: l - c - n, signal: c == 0 && n > 0 })
+    /// Returns the number of items that can be written. The read position is only loaded
+    /// from the buffer if fewer than "wanted" items can be written according to the last one seen.
+    fn free(&mut self, wanted: usize) -> Result<usize, Error> {
+        let l = self.buf.length;
+        let free = l - self.buf.used(self.tail, self.head)?;
+        if free >= wanted {
+            return Ok(free);
+        }
+        self.tail = self.buf.load_pos(self.buf.tail())?;
+        Ok(l - self.buf.used(self.tail, self.head)?)
     }
 
-    /// "Safe" version of send. Will call your closure up to "count" times
-    /// and depend on optimisation to avoid memory copies.
+    fn commit(&mut self, waste: usize, len: usize, n: usize) -> Result<Status, Error> {
+        if n > len {
+            Err(Error::CallbackWroteTooMuch)?
+        }
+        let l = self.buf.length;
+        if waste + n == 0 {
+            return Ok(Status { remaining: l - self.buf.used(self.tail, self.head)?, signal: false });
+        }
+        let old_head = self.head;
+        if waste > 0 {
+            // Must be visible to the receiver before the write position is.
+            self.buf.waste().store(old_head + 1, Ordering::Release);
+        } else if self.buf.offset(old_head) + n >= l && self.buf.waste().load(Ordering::Relaxed) != 0 {
+            // We're at the end of the buffer again, so the receiver must have passed the
+            // wasted items from the last time.
+            self.buf.waste().store(0, Ordering::Release);
+        }
+        self.head = self.buf.advance(self.head, waste + n);
+        self.buf.head().store(self.head, Ordering::Release);
+        // Pairs with the fence in Receiver::read_count: either the receiver sees our write
+        // position before going to sleep, or we see its waiting flag. If it is not waiting,
+        // there is no need to signal it, or to load the read position to find out whether to.
+        fence(Ordering::SeqCst);
+        let signal = self.buf.receiver_waiting().load(Ordering::Relaxed) != 0 && {
+            // It is waiting for more items only if there were fewer than its watermark before
+            // we wrote. It might also have read some of what we just wrote already.
+            self.tail = self.buf.load_pos(self.buf.tail())?;
+            let mark = self.buf.load_mark(self.buf.read_mark());
+            let avail = self.buf.readable(self.tail, self.head)?;
+            avail.saturating_sub(n) < mark && avail >= mark
+        };
+        // dbg!("Send: head = {}, tail = {}, l = {}, n = {}", self.head, self.tail, l, n);
+        Ok(Status { remaining: l - self.buf.used(self.tail, self.head)?, signal })
+    }
+
+    /// Copies as many items from the slice as there is room for into the buffer.
     ///
-    /// # Panics
+    /// Returns the number of items sent. Status::signal is set if any part of the sending
+    /// made the buffer go from empty to non-empty.
+    pub fn send_from(&mut self, items: &[T]) -> Result<(usize, Status), Error> {
+        let mut n = 0;
+        let mut signal = false;
+        loop {
+            let guard = self.reserve(items.len() - n)?;
+            let len = guard.len();
+            unsafe { ptr::copy_nonoverlapping(items[n..].as_ptr(), guard.as_mut_ptr(), len) };
+            let status = guard.commit(len)?;
+            n += len;
+            signal |= status.signal;
+            if len == 0 || n == items.len() {
+                return Ok((n, Status { remaining: status.remaining, signal }));
+            }
+        }
+    }
+
+    /// Sends items from the iterator until either the iterator or the buffer is exhausted.
     ///
-    /// Panics in case the buffer is corrupt.
-    pub fn send_foreach<F: FnMut() -> T>(&mut self, mut count: usize, mut f: F) -> Status {
+    /// Items are only taken from the iterator if there is room for them, so you can pass
+    /// `&mut iter` and continue with the rest of the items later.
+    /// Returns the number of items sent, and the status like `send_from`.
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, items: I) -> Result<(usize, Status), Error> {
+        let mut iter = items.into_iter();
+        let mut n = 0;
+        let mut signal = false;
         loop {
-            let status = self
-                .send(|p, c| {
-                    let mut j = 0;
-                    while j < c && count > 0 {
-                        unsafe { ptr::write(p.offset(j as isize), f()) };
-                        j += 1;
-                        count -= 1;
-                    }
-                    j
-                })
-                .unwrap();
-            if status.remaining == 0 || count == 0 {
-                return status;
+            let guard = self.reserve(usize::MAX)?;
+            let len = guard.len();
+            let mut j = 0;
+            while j < len {
+                match iter.next() {
+                    Some(item) => unsafe { ptr::write(guard.as_mut_ptr().add(j), item) },
+                    None => break,
+                }
+                j += 1;
+            }
+            let status = guard.commit(j)?;
+            n += j;
+            signal |= status.signal;
+            if len == 0 || j < len {
+                return Ok((n, Status { remaining: status.remaining, signal }));
             }
         }
     }
 
     /// Returns number of items that can be written
-    pub fn write_count(&self) -> Result<usize, Error> { Ok(self.buf.length - self.buf.load_count()?) }
+    pub fn write_count(&self) -> Result<usize, Error> {
+        // Pairs with the fence in Receiver::release, so that if we go to sleep because the
+        // buffer is full, the receiver sees our write position and knows to wake us up.
+        fence(Ordering::SeqCst);
+        Ok(self.buf.length - self.buf.used(self.buf.load_pos(self.buf.tail())?, self.head)?)
+    }
+
+    /// Returns the total number of items the buffer can hold
+    pub fn buf_len(&self) -> usize { self.buf.length }
+
+    /// Returns the number of items between the write position and the end of the buffer,
+    /// i e, how many items could be written contiguously if the buffer was empty.
+    pub fn contiguous_room(&self) -> usize {
+        if self.buf.mirrored { self.buf.length } else { self.buf.length - self.buf.offset(self.head) }
+    }
+
+    /// Returns the fingerprint the buffer was set up with.
+    pub fn fingerprint(&self) -> u64 { self.buf.fingerprint() }
+
+    /// Makes attaching to the buffer fail with `Error::FingerprintMismatch`, unless the
+    /// `type_fingerprint` of the attaching side's T is the same as ours.
+    ///
+    /// This is for the side that set up the buffer, before the other side attaches to it.
+    pub fn require_fingerprint(&mut self) { self.buf.require_fingerprint() }
+
+    /// Sets how many items must be writable before the receiver signals us.
+    ///
+    /// This is the low watermark: Status::signal is set on the receiving side when the buffer
+    /// goes from having less room than this to having at least this much room.
+    /// The default is one, i e, when the buffer goes from full to non-full.
+    pub fn set_write_watermark(&mut self, items: usize) { self.buf.write_mark().store(items, Ordering::SeqCst) }
+
+    /// Returns the write watermark, see `set_write_watermark`.
+    pub fn write_watermark(&self) -> usize { self.buf.load_mark(self.buf.write_mark()) }
+
+    /// Returns the read watermark set by the receiver, see `Receiver::set_read_watermark`.
+    pub fn read_watermark(&self) -> usize { self.buf.load_mark(self.buf.read_mark()) }
+
+    /// Tells the receiver whether we might be waiting for it to signal us.
+    ///
+    /// This is true by default, which means that Status::signal is set on the receiving side
+    /// whenever the write watermark is crossed. If we're busy and will check `write_count`
+    /// before going to sleep anyway, set it to false to save the receiver the signalling.
+    /// When setting it back to true before going to sleep, you must call `write_count`
+    /// afterwards and only sleep if there is still not enough room.
+    pub fn set_waiting(&self, waiting: bool) { self.buf.sender_waiting().store(waiting as usize, Ordering::SeqCst) }
 }
 
 impl<T: zerocopy::FromBytes + Copy> Receiver<T> {
@@ -190,63 +632,345 @@ impl<T: zerocopy::FromBytes + Copy> Receiver<T> {
     ///
     /// Since this is a ringbuffer, there might be more items to read even if you
     /// read it all during the closure.
-    pub fn recv<F: FnOnce(*const T, usize) -> usize>(&mut self, f: F) -> Result<Status, Error> {
-        let cb = self.buf.load_count()?;
-        let l = self.buf.length;
-        let n = {
-            let data_start = unsafe { self.buf.data.offset(self.index as isize) };
-            let data_len = cmp::min(self.index + cb, l) - self.index;
+    pub fn recv<F: FnOnce(*const T, usize) -> usize>(&mut self, f: F) -> Result<Status, Error> { self.recv_contiguous(1, f) }
 
-            let n = if data_len == 0 { 0 } else { f(data_start, data_len) };
-            if n > data_len {
-                Err(Error::CallbackReadTooMuch)?
-            }
-            n
-        };
+    /// Like "recv", but the closure is only called if at least "min" items can be read
+    /// contiguously.
+    ///
+    /// This is useful together with `Sender::send_contiguous`: if the sender always writes
+    /// a multiple of "min" items, the receiver can always read them in one go.
+    pub fn recv_contiguous<F: FnOnce(*const T, usize) -> usize>(&mut self, min: usize, f: F) -> Result<Status, Error> {
+        let guard = self.peek_contiguous(min)?;
+        let n = if guard.is_empty() { 0 } else { f(guard.as_ptr(), guard.len()) };
+        guard.release(n)
+    }
+
+    /// Gives access to the items that can be read contiguously, which might be zero items
+    /// if the buffer is empty.
+    ///
+    /// Nothing is removed from the buffer until the guard is released.
+    pub fn peek(&mut self) -> Result<ReadGuard<'_, T>, Error> { self.peek_contiguous(1) }
 
-        let c = self.buf.count().fetch_sub(n, Ordering::AcqRel);
-        self.index = (self.index + n) % l;
-        // dbg!("Recv: cb = {}, c = {}, l = {}, n = {}", cb, c, l, n);
-        return Ok(Status { remaining: c - n, signal: c >= l && n > 0 });
+    /// Like "peek", but gives access to at least "min" contiguous items, or nothing at all.
+    pub fn peek_contiguous(&mut self, min: usize) -> Result<ReadGuard<'_, T>, Error> {
+        let (len, skipped, cb) = self.peek_state(min)?;
+        Ok(ReadGuard { receiver: self, len, skipped, cb, released: false })
     }
 
-    /// "Safe" version of recv. Will call your closure up to "count" times
-    /// and depend on optimisation to avoid memory copies.
+    /// Returns the number of contiguous items available, the number of wasted items skipped,
+    /// and the number of items available (excluding skipped items).
     ///
-    /// # Panics
+    /// The write position is only loaded from the buffer if fewer than "min" contiguous items
+    /// are available according to the last one seen.
+    fn peek_state(&mut self, min: usize) -> Result<(usize, usize, usize), Error> {
+        let mut cb = self.buf.used(self.tail, self.head)?;
+        let mut skipped = self.skip_waste(cb)?;
+        cb -= skipped;
+        let mut len = self.contiguous(cb)?;
+        if len < cmp::max(min, 1) {
+            self.head = self.buf.load_pos(self.buf.head())?;
+            cb = self.buf.used(self.tail, self.head)?;
+            let s = self.skip_waste(cb)?;
+            skipped += s;
+            cb -= s;
+            len = self.contiguous(cb)?;
+        }
+        let len = if len < min { 0 } else { len };
+        Ok((len, skipped, cb))
+    }
+
+    /// Returns how many of the "cb" available items can be read contiguously.
+    fn contiguous(&self, cb: usize) -> Result<usize, Error> {
+        if self.buf.mirrored {
+            return Ok(cb);
+        }
+        let room = self.buf.length - self.buf.offset(self.tail);
+        let data = self.buf.waste_at(self.tail, cb)?.map_or(cb, |(d, _)| d);
+        Ok(cmp::min(room, data))
+    }
+
+    fn release(&mut self, mut skipped: usize, cb: usize, n: usize) -> Result<Status, Error> {
+        let l = self.buf.length;
+        self.tail = self.buf.advance(self.tail, n);
+        if skipped == 0 {
+            // We might have gotten to the wasted part just now.
+            skipped = self.skip_waste(cb - n)?;
+        }
+        if skipped + n == 0 {
+            return Ok(Status { remaining: self.buf.readable(self.tail, self.head)?, signal: false });
+        }
+        self.buf.tail().store(self.tail, Ordering::Release);
+        // Pairs with the fence in Sender::write_count, see Sender::commit.
+        fence(Ordering::SeqCst);
+        let signal = self.buf.sender_waiting().load(Ordering::Relaxed) != 0 && {
+            // It is waiting for room only if there was less than its watermark before we read.
+            self.head = self.buf.load_pos(self.buf.head())?;
+            let used = self.buf.used(self.tail, self.head)?;
+            let mark = self.buf.load_mark(self.buf.write_mark());
+            l.saturating_sub(used + skipped + n) < mark && l - used >= mark
+        };
+        // dbg!("Recv: head = {}, tail = {}, l = {}, n = {}", self.head, self.tail, l, n);
+        Ok(Status { remaining: self.buf.readable(self.tail, self.head)?, signal })
+    }
+
+    /// If we're at the wasted part at the end of the buffer, move to the start of the buffer,
+    /// and return how many items were skipped.
+    fn skip_waste(&mut self, cb: usize) -> Result<usize, Error> {
+        match self.buf.waste_at(self.tail, cb)? {
+            Some((0, w)) => {
+                self.tail = self.buf.advance(self.tail, w);
+                Ok(w)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Copies as many items as are available, and fit, into the slice.
     ///
-    /// Panics in case the buffer is corrupt.
-    pub fn recv_foreach<F: FnMut(T)>(&mut self, mut count: usize, mut f: F) -> Status {
+    /// Returns the number of items received. Status::signal is set if any part of the receiving
+    /// made the buffer go from full to non-full.
+    pub fn recv_into(&mut self, items: &mut [T]) -> Result<(usize, Status), Error> {
+        let mut n = 0;
+        let mut signal = false;
         loop {
-            let status = self
-                .recv(|p, c| {
-                    let mut j = 0;
-                    while j < c && count > 0 {
-                        f(unsafe { ptr::read(p.offset(j as isize)) });
-                        count -= 1;
-                        j += 1;
-                    }
-                    j
-                })
-                .unwrap();
-            if status.remaining == 0 || count == 0 {
-                return status;
+            let guard = self.peek()?;
+            let len = cmp::min(guard.len(), items.len() - n);
+            unsafe { ptr::copy_nonoverlapping(guard.as_ptr(), items[n..].as_mut_ptr(), len) };
+            let status = guard.release(len)?;
+            n += len;
+            signal |= status.signal;
+            if len == 0 || n == items.len() {
+                return Ok((n, Status { remaining: status.remaining, signal }));
             }
         }
     }
 
+    /// Returns an iterator that copies out the items that are currently available.
+    ///
+    /// Items are removed from the buffer as the iterator moves on to the next contiguous part,
+    /// and when it is dropped or finished.
+    pub fn drain(&mut self) -> Result<Drain<'_, T>, Error> {
+        let limit = self.read_count()?;
+        let (len, skipped, cb) = self.peek_state(1)?;
+        let status = Status { remaining: limit, signal: false };
+        Ok(Drain { receiver: self, limit, len, skipped, cb, taken: 0, status, error: None, finished: false })
+    }
+
     /// Returns number of items that can be read
-    pub fn read_count(&self) -> Result<usize, Error> { self.buf.load_count() }
+    pub fn read_count(&self) -> Result<usize, Error> {
+        // Pairs with the fence in Sender::commit, see Sender::write_count.
+        fence(Ordering::SeqCst);
+        self.buf.readable(self.tail, self.buf.load_pos(self.buf.head())?)
+    }
+
+    /// Returns the total number of items the buffer can hold
+    pub fn buf_len(&self) -> usize { self.buf.length }
+
+    /// Returns the fingerprint the buffer was set up with.
+    pub fn fingerprint(&self) -> u64 { self.buf.fingerprint() }
+
+    /// Like `Sender::require_fingerprint`.
+    pub fn require_fingerprint(&mut self) { self.buf.require_fingerprint() }
+
+    /// Sets how many items must be readable before the sender signals us.
+    ///
+    /// This is the high watermark: Status::signal is set on the sending side when the buffer
+    /// goes from holding fewer items than this to holding at least this many items.
+    /// The default is one, i e, when the buffer goes from empty to non-empty.
+    pub fn set_read_watermark(&mut self, items: usize) { self.buf.read_mark().store(items, Ordering::SeqCst) }
+
+    /// Returns the read watermark, see `set_read_watermark`.
+    pub fn read_watermark(&self) -> usize { self.buf.load_mark(self.buf.read_mark()) }
+
+    /// Returns the write watermark set by the sender, see `Sender::set_write_watermark`.
+    pub fn write_watermark(&self) -> usize { self.buf.load_mark(self.buf.write_mark()) }
+
+    /// Tells the sender whether we might be waiting for it to signal us.
+    ///
+    /// See `Sender::set_waiting`; when setting it back to true, you must call `read_count`
+    /// afterwards and only sleep if there is still not enough to read.
+    pub fn set_waiting(&self, waiting: bool) { self.buf.receiver_waiting().store(waiting as usize, Ordering::SeqCst) }
 
     /// Assume a ringbuf is set up at the location.
     ///
-    /// A buffer where the first 64 bytes are zero is okay.
+    /// The buffer must have been set up with `init`, for the same item type and length.
+    /// Receiving continues from the read position stored in the buffer, so if a previous receiver
+    /// went away, this one takes over where it stopped. Items that the previous receiver
+    /// had looked at, but not released, are received again.
     ///
     /// # Safety
     ///
     /// You must ensure that "data" points to a readable and writable memory area of "length" bytes.
-    pub unsafe fn attach(data: *mut u8, length: usize) -> Result<Self, Error> {
-        Ok(Self { buf: Buf::attach(data, length, false)?, index: 0 })
+    pub unsafe fn attach(data: *mut u8, length: usize) -> Result<Self, Error> { Self::resume(Buf::attach(data, length, None)?) }
+
+    /// Assume a ringbuf is set up at the location, with the data mapped twice.
+    ///
+    /// The header is at "data" and the items start at "data + header_size". Since the items
+    /// are mirrored, all available items can always be accessed contiguously.
+    ///
+    /// # Safety
+    ///
+    /// You must ensure that "data" points to a readable and writable memory area of
+    /// "header_size" bytes, followed by "capacity" items, followed by a mirror of these items.
+    pub unsafe fn attach_mirrored(data: *mut u8, header_size: usize, capacity: usize) -> Result<Self, Error> {
+        Self::resume(Buf::attach_header(data, header_size, capacity, true, None)?)
+    }
+
+    fn resume(buf: Buf<T>) -> Result<Self, Error> {
+        let (tail, head) = buf.load_positions()?;
+        buf.waste_at(tail, 0)?;
+        buf.receiver_waiting().store(1, Ordering::SeqCst);
+        Ok(Self { buf, tail, head })
+    }
+}
+
+/// Items reserved for writing, returned from `Sender::reserve`.
+///
+/// Nothing is sent until `commit` is called; dropping the guard leaves the buffer as it was.
+pub struct WriteGuard<'a, T> {
+    sender: &'a mut Sender<T>,
+    start: usize,
+    len: usize,
+    waste: usize,
+}
+
+impl<T: zerocopy::AsBytes + Copy> WriteGuard<'_, T> {
+    /// Pointer to the first reserved item. This is an "out" parameter, i e, it can be written to
+    /// using e g `std::ptr::write`.
+    pub fn as_mut_ptr(&self) -> *mut T { unsafe { self.sender.buf.data.add(self.start) } }
+
+    /// Number of reserved items.
+    pub fn len(&self) -> usize { self.len }
+
+    /// Returns true if no items could be reserved.
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Sends the first "count" reserved items.
+    pub fn commit(self, count: usize) -> Result<Status, Error> { self.sender.commit(self.waste, self.len, count) }
+}
+
+/// Items available for reading, returned from `Receiver::peek`.
+///
+/// Nothing is removed from the buffer until `release` is called; dropping the guard leaves
+/// all items in the buffer.
+pub struct ReadGuard<'a, T: zerocopy::FromBytes + Copy> {
+    receiver: &'a mut Receiver<T>,
+    len: usize,
+    skipped: usize,
+    cb: usize,
+    released: bool,
+}
+
+impl<T: zerocopy::FromBytes + Copy> ReadGuard<'_, T> {
+    /// Pointer to the first available item. It can be read from using e g `std::ptr::read`.
+    pub fn as_ptr(&self) -> *const T { unsafe { self.receiver.buf.data.add(self.receiver.buf.offset(self.receiver.tail)) } }
+
+    /// Number of available items.
+    pub fn len(&self) -> usize { self.len }
+
+    /// Returns true if there are no available items.
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Removes the first "count" available items from the buffer.
+    pub fn release(mut self, count: usize) -> Result<Status, Error> {
+        if count > self.len {
+            Err(Error::CallbackReadTooMuch)?
+        }
+        self.released = true;
+        self.receiver.release(self.skipped, self.cb, count)
+    }
+}
+
+impl<T: zerocopy::FromBytes + Copy> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        if !self.released {
+            // Wasted items at the end of the buffer might have been skipped already.
+            let _ = self.receiver.release(self.skipped, self.cb, 0);
+        }
+    }
+}
+
+/// Iterator returned from `Receiver::drain`.
+pub struct Drain<'a, T: zerocopy::FromBytes + Copy> {
+    receiver: &'a mut Receiver<T>,
+    /// Number of items left to drain
+    limit: usize,
+    len: usize,
+    skipped: usize,
+    cb: usize,
+    /// Number of items taken from the current contiguous part
+    taken: usize,
+    status: Status,
+    error: Option<Error>,
+    finished: bool,
+}
+
+impl<T: zerocopy::FromBytes + Copy> Drain<'_, T> {
+    /// Removes the items taken so far from the buffer.
+    fn flush(&mut self) -> Result<(), Error> {
+        let status = self.receiver.release(self.skipped, self.cb, self.taken)?;
+        self.status.remaining = status.remaining;
+        self.status.signal |= status.signal;
+        self.len = 0;
+        self.skipped = 0;
+        self.cb = 0;
+        self.taken = 0;
+        Ok(())
+    }
+
+    fn next_part(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        let (len, skipped, cb) = self.receiver.peek_state(1)?;
+        self.len = len;
+        self.skipped = skipped;
+        self.cb = cb;
+        Ok(())
+    }
+
+    /// Removes the items taken from the buffer, and returns the status.
+    ///
+    /// Status::signal is set if the buffer went from full to non-full at any point while draining.
+    /// An error is returned if the buffer was found to be corrupt during the iteration.
+    pub fn finish(mut self) -> Result<Status, Error> {
+        self.finished = true;
+        if let Some(e) = self.error.take() {
+            Err(e)?
+        }
+        self.flush()?;
+        Ok(self.status)
+    }
+}
+
+impl<T: zerocopy::FromBytes + Copy> Iterator for Drain<'_, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        if self.limit == 0 || self.error.is_some() {
+            return None;
+        }
+        if self.taken == self.len {
+            if let Err(e) = self.next_part() {
+                self.error = Some(e);
+                return None;
+            }
+            if self.len == 0 {
+                return None;
+            }
+        }
+        let index = self.receiver.buf.offset(self.receiver.tail) + self.taken;
+        let item = unsafe { ptr::read(self.receiver.buf.data.add(index)) };
+        self.taken += 1;
+        self.limit -= 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { (0, Some(self.limit)) }
+}
+
+impl<T: zerocopy::FromBytes + Copy> Drop for Drain<'_, T> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.flush();
+        }
     }
 }
 
@@ -255,7 +979,7 @@ mod tests {
 
     #[test]
     fn simple_test() {
-        let mut v = vec![10; 100];
+        let mut v = vec![10; 300];
         let (mut s, mut r) = super::channel(&mut v);
         // is it empty?
         r.recv(|_, _| panic!()).unwrap();
@@ -279,11 +1003,7 @@ mod tests {
         .unwrap();
         r.recv(|_, _| panic!()).unwrap();
 
-        let mut i = 6;
-        s.send_foreach(2, || {
-            i += 1;
-            i
-        });
+        assert_eq!(s.send_from(&[7, 8]).unwrap().0, 2);
         r.recv(|d, l| {
             assert_eq!(l, 2);
             let x = unsafe { std::ptr::read(d as *const [u16; 2]) };
@@ -293,9 +1013,126 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn contiguous_test() {
+        let mut q: Vec<u8> = vec![66; super::channel_bufsize::<u16>(10)];
+        let (mut s, mut r): (super::Sender<u16>, super::Receiver<u16>) = super::channel(&mut q);
+        s.send(|_, l| {
+            assert_eq!(l, 10);
+            7
+        })
+        .unwrap();
+        r.recv(|_, l| {
+            assert_eq!(l, 7);
+            5
+        })
+        .unwrap();
+        // Three items left at the end, not enough.
+        s.send_contiguous(4, |d, l| {
+            assert_eq!(l, 5);
+            unsafe { std::ptr::write(d as *mut [u16; 4], [1, 2, 3, 4]) };
+            4
+        })
+        .unwrap();
+        assert_eq!(s.write_count().unwrap(), 1);
+        s.send_contiguous(2, |_, _| panic!()).unwrap();
+        r.recv(|_, l| {
+            assert_eq!(l, 2);
+            2
+        })
+        .unwrap();
+        // The wasted part should be skipped.
+        r.recv_contiguous(4, |d, l| {
+            assert_eq!(l, 4);
+            assert_eq!([1, 2, 3, 4], unsafe { std::ptr::read(d as *const [u16; 4]) });
+            1
+        })
+        .unwrap();
+        assert_eq!(r.read_count().unwrap(), 3);
+        r.recv_contiguous(4, |_, _| panic!()).unwrap();
+        let status = r.recv(|_, l| {
+            assert_eq!(l, 3);
+            3
+        })
+        .unwrap();
+        assert_eq!(status.remaining, 0);
+        assert_eq!(s.write_count().unwrap(), 10);
+        s.send_contiguous(6, |_, l| {
+            assert_eq!(l, 6);
+            6
+        })
+        .unwrap();
+        // Filling up to the end wraps around as usual.
+        s.send(|_, l| {
+            assert_eq!(l, 4);
+            0
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn waste_count_test() {
+        let mut q: Vec<u8> = vec![0; super::channel_bufsize::<u16>(10)];
+        let (mut s, mut r): (super::Sender<u16>, super::Receiver<u16>) = super::channel(&mut q);
+        s.send_from(&[0; 5]).unwrap();
+        assert_eq!(r.recv_into(&mut [0; 5]).unwrap().0, 5);
+        s.send_from(&[9, 9]).unwrap();
+        r.set_read_watermark(7);
+        r.set_waiting(true);
+        // Three items are wasted at the end, which do not count towards the watermark.
+        let status = s
+            .send_contiguous(4, |d, l| {
+                assert_eq!(l, 5);
+                unsafe { std::ptr::write(d as *mut [u16; 4], [1, 2, 3, 4]) };
+                4
+            })
+            .unwrap();
+        assert!(!status.signal);
+        assert_eq!(r.read_count().unwrap(), 6);
+        assert_eq!(r.recv_contiguous(5, |_, _| panic!()).unwrap().remaining, 6);
+        let d = r.drain().unwrap();
+        assert_eq!(d.size_hint(), (0, Some(6)));
+        assert_eq!(d.collect::<Vec<_>>(), [9, 9, 1, 2, 3, 4]);
+        assert_eq!(r.read_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn guard_test() {
+        let mut q: Vec<u8> = vec![66; super::channel_bufsize::<u16>(4)];
+        let (mut s, mut r): (super::Sender<u16>, super::Receiver<u16>) = super::channel(&mut q);
+        {
+            let g = s.reserve(3).unwrap();
+            assert_eq!(g.len(), 3);
+            unsafe { std::ptr::write(g.as_mut_ptr() as *mut [u16; 3], [1, 2, 3]) };
+        }
+        assert!(r.peek().unwrap().is_empty());
+
+        let g = s.reserve(3).unwrap();
+        unsafe { std::ptr::write(g.as_mut_ptr() as *mut [u16; 3], [1, 2, 3]) };
+        assert!(g.commit(4).is_err());
+        let g = s.reserve(3).unwrap();
+        unsafe { std::ptr::write(g.as_mut_ptr() as *mut [u16; 2], [4, 5]) };
+        let status = g.commit(2).unwrap();
+        assert!(status.signal);
+        assert_eq!(status.remaining, 2);
+
+        let g = r.peek().unwrap();
+        assert_eq!(g.len(), 2);
+        assert_eq!(unsafe { *g.as_ptr() }, 4);
+        drop(g);
+        let g = r.peek().unwrap();
+        assert_eq!(unsafe { *g.as_ptr().add(1) }, 5);
+        g.release(1).unwrap();
+        let g = r.peek().unwrap();
+        assert_eq!(g.len(), 1);
+        assert_eq!(unsafe { *g.as_ptr() }, 5);
+        assert!(g.release(2).is_err());
+        assert_eq!(r.read_count().unwrap(), 1);
+    }
+
     #[test]
     fn full_buf_test() {
-        assert_eq!(super::channel_bufsize::<u16>(3), 64 + 3 * 2);
+        assert_eq!(super::channel_bufsize::<u16>(3), 192 + 3 * 2);
         let mut q: Vec<u8> = vec![66; super::channel_bufsize::<u16>(3)];
         let (mut s, mut r): (super::Sender<u16>, super::Receiver<u16>) = super::channel(&mut q);
         s.send(|dd, l| {
@@ -306,12 +1143,9 @@ mod tests {
             2
         })
         .unwrap();
-        let mut called = false;
-        s.send_foreach(2, || {
-            assert_eq!(called, false);
-            called = true;
-            10
-        });
+        let mut items = [10, 11].iter().copied();
+        assert_eq!(s.extend(&mut items).unwrap().0, 1);
+        assert_eq!(items.next(), Some(11));
         s.send(|_, _| panic!()).unwrap();
         r.recv(|_, l| {
             assert_eq!(l, 3);
@@ -338,11 +1172,131 @@ mod tests {
             2
         })
         .unwrap();
-        let mut called = false;
-        r.recv_foreach(56, |d| {
-            assert_eq!(called, false);
-            called = true;
-            assert_eq!(d, 1);
-        });
+        let v: Vec<u16> = r.drain().unwrap().collect();
+        assert_eq!(v, [1]);
+    }
+
+    #[test]
+    fn copy_test() {
+        let mut q: Vec<u8> = vec![66; super::channel_bufsize::<u16>(5)];
+        let (mut s, mut r): (super::Sender<u16>, super::Receiver<u16>) = super::channel(&mut q);
+        let mut out = [0u16; 10];
+        assert_eq!(s.send_from(&[1, 2, 3]).unwrap().0, 3);
+        assert_eq!(r.recv_into(&mut out[..2]).unwrap().0, 2);
+        // These wrap around the end of the buffer
+        let (n, status) = s.send_from(&[4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(n, 4);
+        assert!(!status.signal);
+        assert_eq!(status.remaining, 0);
+        let (n, status) = r.recv_into(&mut out).unwrap();
+        assert_eq!(&out[..n], &[3, 4, 5, 6, 7]);
+        assert!(status.signal);
+
+        let (n, status) = s.extend(10..).unwrap();
+        assert_eq!(n, 5);
+        assert!(status.signal);
+        let mut d = r.drain().unwrap();
+        assert_eq!(d.next(), Some(10));
+        assert_eq!(d.next(), Some(11));
+        drop(d);
+        assert_eq!(r.read_count().unwrap(), 3);
+        let d = r.drain().unwrap();
+        assert_eq!(d.size_hint(), (0, Some(3)));
+        assert_eq!(d.sum::<u16>(), 12 + 13 + 14);
+        assert_eq!(r.read_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn header_test() {
+        use super::{Error, Receiver, Sender};
+        let mut q = vec![0u64; super::channel_bufsize::<u32>(8) / 8];
+        let (p, len) = (q.as_mut_ptr() as *mut u8, q.len() * 8);
+        assert!(matches!(unsafe { Sender::<u32>::attach(p, len) }, Err(Error::HeaderMagic)));
+        unsafe { super::init::<u32>(p, len, 5).unwrap() };
+        let r = unsafe { Receiver::<u32>::attach(p, len).unwrap() };
+        assert_eq!((r.buf_len(), r.fingerprint()), (8, 5));
+        assert!(matches!(unsafe { Sender::<u64>::attach(p, len) }, Err(Error::ItemLayout)));
+        assert!(matches!(unsafe { Sender::<u32>::attach(p, len - 8) }, Err(Error::CapacityMismatch)));
+        assert!(matches!(unsafe { Sender::<u32>::attach_mirrored(p, 256, 8) }, Err(Error::MirroredMismatch)));
+        let mut s = unsafe { Sender::<u32>::attach(p, len).unwrap() };
+        s.require_fingerprint();
+        assert!(matches!(unsafe { Receiver::<i32>::attach(p, len) }, Err(Error::FingerprintMismatch)));
+        assert_eq!(unsafe { Receiver::<u32>::attach(p, len).unwrap() }.fingerprint(), super::type_fingerprint::<u32>());
+        q[0] += 1 << 32;
+        assert!(matches!(unsafe { Sender::<u32>::attach(p, len) }, Err(Error::HeaderVersion)));
+    }
+
+    #[test]
+    fn watermark_test() {
+        let mut v = vec![0u8; super::channel_bufsize::<u32>(8)];
+        let (mut s, mut r) = super::channel::<u32>(&mut v);
+        r.set_read_watermark(4);
+        assert_eq!((s.read_watermark(), s.write_watermark()), (4, 1));
+        assert!(!s.send_from(&[1, 2, 3]).unwrap().1.signal);
+        assert!(s.send_from(&[4, 5]).unwrap().1.signal);
+        assert!(!s.send_from(&[6]).unwrap().1.signal);
+        assert_eq!(r.recv_into(&mut [0; 5]).unwrap().0, 5);
+        assert!(!s.send_from(&[7, 8]).unwrap().1.signal);
+        assert!(s.send_from(&[9]).unwrap().1.signal);
+
+        s.set_write_watermark(3);
+        assert_eq!(s.send_from(&[0; 8]).unwrap().0, 4);
+        assert!(!r.recv_into(&mut [0; 2]).unwrap().1.signal);
+        assert!(r.recv_into(&mut [0; 2]).unwrap().1.signal);
+        assert!(!r.recv_into(&mut [0; 2]).unwrap().1.signal);
+        // Out of range watermarks are clamped
+        s.set_write_watermark(100);
+        assert_eq!(r.write_watermark(), 8);
+        r.set_read_watermark(0);
+        assert_eq!(s.read_watermark(), 1);
+    }
+
+    #[test]
+    fn waiting_test() {
+        let mut v = vec![0u8; super::channel_bufsize::<u32>(4)];
+        let (mut s, mut r) = super::channel::<u32>(&mut v);
+        r.set_waiting(false);
+        assert!(!s.send_from(&[1]).unwrap().1.signal);
+        assert_eq!(r.recv_into(&mut [0; 4]).unwrap().0, 1);
+        r.set_waiting(true);
+        assert!(s.send_from(&[2, 3, 4, 5]).unwrap().1.signal);
+        s.set_waiting(false);
+        assert!(!r.recv_into(&mut [0; 1]).unwrap().1.signal);
+        s.set_waiting(true);
+        assert_eq!(s.send_from(&[6]).unwrap().0, 1);
+        assert!(r.recv_into(&mut [0; 1]).unwrap().1.signal);
+    }
+
+    #[test]
+    fn reattach_test() {
+        use super::{Receiver, Sender};
+        let mut q = vec![0u64; super::channel_bufsize::<u32>(8) / 4];
+        let (p, len) = (q.as_mut_ptr() as *mut u8, super::channel_bufsize::<u32>(8));
+        unsafe { super::init::<u32>(p, len, 0).unwrap() };
+        let mut s: Sender<u32> = unsafe { Sender::attach(p, len).unwrap() };
+        let mut r: Receiver<u32> = unsafe { Receiver::attach(p, len).unwrap() };
+        assert_eq!(s.send_from(&[1, 2, 3, 4, 5, 6]).unwrap().0, 6);
+        assert_eq!(r.recv_into(&mut [0; 5]).unwrap().0, 5);
+        // Two items are wasted at the end.
+        let g = s.reserve_contiguous(3).unwrap();
+        unsafe { std::ptr::write(g.as_mut_ptr() as *mut [u32; 3], [7, 8, 9]) };
+        g.commit(3).unwrap();
+
+        // The receiver goes away while looking at the items after the wasted ones.
+        let mut out = [0; 8];
+        assert_eq!(r.recv_into(&mut out[..1]).unwrap().0, 1);
+        let g = r.peek().unwrap();
+        assert_eq!(unsafe { *g.as_ptr() }, 7);
+        std::mem::forget(g);
+        let mut r: Receiver<u32> = unsafe { Receiver::attach(p, len).unwrap() };
+        assert_eq!(r.recv_into(&mut out).unwrap().0, 3);
+        assert_eq!(&out[..3], &[7, 8, 9]);
+
+        // The sender goes away after writing the waste, but before the write position.
+        s.buf.waste().store(s.head + 1, std::sync::atomic::Ordering::Release);
+        let mut s: Sender<u32> = unsafe { Sender::attach(p, len).unwrap() };
+        assert_eq!(s.send_from(&[10, 11, 12, 13, 14, 15, 16]).unwrap().0, 7);
+        assert_eq!(r.recv_into(&mut out).unwrap().0, 7);
+        assert_eq!(&out[..7], &[10, 11, 12, 13, 14, 15, 16]);
     }
 }
@@ -0,0 +1,239 @@
+//! A ringbuffer with one producer and many consumers, where every consumer sees every item.
+//!
+//! The producer is never blocked: when the buffer is full, it overwrites the oldest items.
+//! Every consumer keeps its own read position privately, and consumers that fall behind
+//! are told how many items they lost. Consumers never write to the buffer, so they can work on
+//! a read-only mapping.
+
+use super::{Error, Status, CACHE_LINE_SIZE};
+use std::mem::size_of;
+use std::{cmp, ptr};
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+
+/// An item together with a sequence number, so that receivers can tell whether it was
+/// overwritten while they read it.
+#[repr(C)]
+struct Slot<T> {
+    /// Position of the item in this slot, plus one. Written when the item is committed.
+    seq: AtomicUsize,
+    item: T,
+}
+
+#[derive(Copy, Clone)]
+struct Buf<T> {
+    slots: *mut Slot<T>,
+    written_ptr: *const AtomicUsize,
+    length: usize,
+}
+
+unsafe impl<T> Send for Buf<T> {}
+unsafe impl<T> Sync for Buf<T> {}
+
+/// Sender half. There must be only one of them attached to a buffer.
+pub struct Sender<T> {
+    buf: Buf<T>,
+    index: usize,
+}
+
+/// Receiver half. There can be many of them attached to the same buffer.
+pub struct Receiver<T> {
+    buf: Buf<T>,
+    index: usize,
+}
+
+/// Status returned from `Receiver::recv`.
+#[derive(Copy, Clone, Debug)]
+pub struct RecvStatus {
+    /// Number of items received
+    pub received: usize,
+    /// Number of items that were overwritten before this receiver could read them
+    pub lost: usize,
+    /// Number of remaining items that can be immediately read
+    pub remaining: usize,
+}
+
+/// Use this utility function to figure out how big buffer you need to allocate.
+pub fn channel_bufsize<T>(capacity: usize) -> usize { capacity * size_of::<Slot<T>>() + CACHE_LINE_SIZE }
+
+impl<T> Buf<T> {
+    #[inline]
+    fn written(&self) -> &AtomicUsize { unsafe { &*self.written_ptr } }
+
+    #[inline]
+    fn seq(&self, pos: usize) -> &AtomicUsize { unsafe { &(*self.slots.add(pos % self.length)).seq } }
+
+    #[inline]
+    fn item(&self, pos: usize) -> *mut T { unsafe { ptr::addr_of_mut!((*self.slots.add(pos % self.length)).item) } }
+
+    unsafe fn attach(data: *mut u8, length: usize) -> Result<Self, Error> {
+        use Error::*;
+        if length < CACHE_LINE_SIZE + size_of::<Slot<T>>() {
+            Err(BufTooSmall)?
+        }
+        if length >= isize::MAX as usize {
+            Err(BufTooBig)?
+        }
+        let r = Self {
+            written_ptr: data as *const AtomicUsize,
+            slots: data.add(CACHE_LINE_SIZE) as _,
+            length: (length - CACHE_LINE_SIZE) / size_of::<Slot<T>>(),
+        };
+        if (data as usize) % std::mem::align_of::<AtomicUsize>() != 0 {
+            Err(BufUnaligned)?
+        }
+        if (r.slots as usize) % std::mem::align_of::<Slot<T>>() != 0 {
+            Err(BufUnaligned)?
+        }
+        Ok(r)
+    }
+}
+
+impl<T: zerocopy::AsBytes + Copy> Sender<T> {
+    /// Assume a ringbuf is set up at the location.
+    ///
+    /// A buffer where the first 64 bytes are zero is okay.
+    ///
+    /// # Safety
+    ///
+    /// You must ensure that "data" points to a readable and writable memory area of "length" bytes.
+    pub unsafe fn attach(data: *mut u8, length: usize) -> Result<Self, Error> {
+        let buf = Buf::attach(data, length)?;
+        let index = buf.written().load(Ordering::Acquire);
+        Ok(Self { buf, index })
+    }
+
+    /// Writes all items to the buffer, overwriting the oldest items if necessary.
+    ///
+    /// Status::signal is set if anything was written.
+    pub fn send(&mut self, items: &[T]) -> Status {
+        for item in items {
+            let seq = self.buf.seq(self.index);
+            // Mark the slot as being written, so that receivers reading it at the same time can tell.
+            seq.store(0, Ordering::Release);
+            fence(Ordering::Release);
+            unsafe { ptr::write_volatile(self.buf.item(self.index), *item) };
+            self.index = self.index.wrapping_add(1);
+            seq.store(self.index, Ordering::Release);
+            self.buf.written().store(self.index, Ordering::Release);
+        }
+        Status { remaining: self.buf.length, signal: !items.is_empty() }
+    }
+
+    /// Returns the total number of items the buffer can hold
+    pub fn buf_len(&self) -> usize { self.buf.length }
+}
+
+impl<T: zerocopy::FromBytes + Copy> Receiver<T> {
+    /// Assume a ringbuf is set up at the location.
+    ///
+    /// The receiver starts at the item written next, i e, items already in the buffer are
+    /// not received.
+    ///
+    /// # Safety
+    ///
+    /// You must ensure that "data" points to a readable memory area of "length" bytes.
+    /// The receiver never writes to the memory area.
+    pub unsafe fn attach(data: *const u8, length: usize) -> Result<Self, Error> {
+        let buf = Buf::attach(data as *mut u8, length)?;
+        let index = buf.written().load(Ordering::Acquire);
+        Ok(Self { buf, index })
+    }
+
+    fn load_written(&self) -> Result<usize, Error> {
+        let w = self.buf.written().load(Ordering::Acquire);
+        if w.wrapping_sub(self.index) > isize::MAX as usize {
+            // The write position went backwards
+            Err(Error::BufCorrupt)?
+        }
+        Ok(w)
+    }
+
+    /// Skips ahead to the oldest item not yet overwritten, returning the number of items skipped.
+    fn catch_up(&mut self, written: usize) -> usize {
+        let unread = written.wrapping_sub(self.index);
+        if unread <= self.buf.length {
+            return 0;
+        }
+        let lost = unread - self.buf.length;
+        self.index = self.index.wrapping_add(lost);
+        lost
+    }
+
+    /// Receives items into "items", in order.
+    pub fn recv(&mut self, items: &mut [T]) -> Result<RecvStatus, Error> {
+        let mut written = self.load_written()?;
+        let mut lost = self.catch_up(written);
+        let mut n = 0;
+        while n < items.len() && self.index != written {
+            let seq = self.buf.seq(self.index);
+            let s1 = seq.load(Ordering::Acquire);
+            let item = unsafe { ptr::read_volatile(self.buf.item(self.index)) };
+            fence(Ordering::Acquire);
+            let s2 = seq.load(Ordering::Relaxed);
+            if s1 != self.index.wrapping_add(1) || s1 != s2 {
+                // The sender overwrote the item while we were reading it.
+                written = self.load_written()?;
+                let skipped = self.catch_up(written.wrapping_add(1));
+                if skipped == 0 {
+                    self.index = self.index.wrapping_add(1);
+                }
+                lost += cmp::max(skipped, 1);
+                continue;
+            }
+            items[n] = item;
+            n += 1;
+            self.index = self.index.wrapping_add(1);
+        }
+        Ok(RecvStatus { received: n, lost, remaining: self.read_count()? })
+    }
+
+    /// Returns number of items that can be read, including items that will be lost when reading.
+    pub fn read_count(&self) -> Result<usize, Error> { Ok(self.load_written()?.wrapping_sub(self.index)) }
+
+    /// Returns the total number of items the buffer can hold
+    pub fn buf_len(&self) -> usize { self.buf.length }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwrite() {
+        let mut v = vec![0u64; channel_bufsize::<u32>(4) / 8];
+        let (p, len) = (v.as_mut_ptr() as *mut u8, v.len() * 8);
+        let mut s: Sender<u32> = unsafe { Sender::attach(p, len).unwrap() };
+        let mut r1: Receiver<u32> = unsafe { Receiver::attach(p, len).unwrap() };
+        assert_eq!(s.buf_len(), 4);
+        assert!(s.send(&[1, 2, 3]).signal);
+        let mut r2: Receiver<u32> = unsafe { Receiver::attach(p, len).unwrap() };
+        let mut out = [0u32; 10];
+        let status = r1.recv(&mut out[..2]).unwrap();
+        assert_eq!((status.received, status.lost, status.remaining), (2, 0, 1));
+        assert_eq!(&out[..2], &[1, 2]);
+        assert_eq!(r2.recv(&mut out).unwrap().received, 0);
+
+        s.send(&[4, 5, 6, 7, 8]);
+        let status = r1.recv(&mut out).unwrap();
+        assert_eq!((status.received, status.lost, status.remaining), (4, 2, 0));
+        assert_eq!(&out[..4], &[5, 6, 7, 8]);
+        let status = r2.recv(&mut out).unwrap();
+        assert_eq!((status.received, status.lost), (4, 1));
+        assert_eq!(&out[..4], &[5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn overwritten_while_reading() {
+        let mut v = vec![0u64; channel_bufsize::<u32>(4) / 8];
+        let (p, len) = (v.as_mut_ptr() as *mut u8, v.len() * 8);
+        let mut s: Sender<u32> = unsafe { Sender::attach(p, len).unwrap() };
+        let mut r: Receiver<u32> = unsafe { Receiver::attach(p, len).unwrap() };
+        s.send(&[1, 2, 3, 4]);
+        // Simulate that the sender is halfway through overwriting the first item.
+        s.buf.seq(0).store(0, Ordering::Relaxed);
+        let mut out = [0u32; 10];
+        let status = r.recv(&mut out).unwrap();
+        assert_eq!((status.received, status.lost), (3, 1));
+        assert_eq!(&out[..3], &[2, 3, 4]);
+    }
+}
@@ -1,32 +1,416 @@
 //! Creates shared memory ring buffers to be used between untrusted processes.
 //!
-//! The information to be transferred between processes through other means (pipes or D-Bus) is:
+//! The information to be transferred between processes through other means (pipes, D-Bus, or
+//! a unix socket using the `handshake` module) is bundled in a `RingDescriptor`:
 //!  * capacity
+//!  * layout, see `Layout`
 //!  * memfd file descriptor
 //!  * empty signal file descriptor
 //!  * full signal file descriptor
+//!
+//! Ringbuffers set up with `new_futex` wake each other up through futex words in the shared memory
+//! instead, so only the capacity and the memfd need to be transferred.
+//!
+//! With the "tokio" feature enabled, the `tokio` module has async versions of `Sender` and `Receiver`.
+//! With the "futures" feature enabled, `Receiver` implements `futures::Stream` and `Sender`
+//! implements `futures::Sink`, independently of the async runtime.
+//! With the "mio" feature enabled, they both implement `mio::event::Source`.
+//! With the "dbus" feature enabled, the `dbus` module helps exporting ringbuffers from, and opening
+//! them through, D-Bus methods.
+//!
+//! To wait for many ringbuffers on one thread, add them to a `Selector`.
+//!
+//! For request/response traffic, a `Duplex` sets up two ringbuffers in one memfd, with one
+//! eventfd for each side instead of two for each ringbuffer.
 
 use super::Error;
 use crate::mem::mfd::{HugetlbSize, MemfdOptions};
 use crate::ringbuf::Status;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::slice::from_raw_parts;
 use std::slice::from_raw_parts_mut;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "dbus")]
+pub mod dbus;
+#[cfg(feature = "futures")]
+mod futures;
+pub mod handshake;
+#[cfg(feature = "mio")]
+mod mio;
+mod descriptor;
+mod duplex;
+mod selector;
+pub mod server;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+pub use descriptor::{Layout, RingDescriptor};
+pub use duplex::Duplex;
+pub use selector::Selector;
+
+enum Mapping {
+    Raw(memmap2::MmapRaw),
+    Mirrored(crate::mem::MirroredMmap),
+}
+
+impl Mapping {
+    fn as_mut_ptr(&self) -> *mut u8 {
+        match self {
+            Mapping::Raw(m) => m.as_mut_ptr(),
+            Mapping::Mirrored(m) => m.as_mut_ptr(),
+        }
+    }
+
+    fn lock(&mut self) -> Result<(), std::io::Error> {
+        match self {
+            Mapping::Raw(m) => m.lock(),
+            Mapping::Mirrored(m) => m.lock(),
+        }
+    }
+}
+
+/// How one side wakes up the other side.
+enum Signal {
+    /// An eventfd, which is passed to the other side together with the memfd.
+    Eventfd(File),
+    /// A futex word in the ringbuffer header, see `ringbuf::futex_words`.
+    Futex(*const AtomicU32),
+    /// An eventfd shared by both halves of a `Duplex`, which take turns reading it.
+    Shared(Arc<duplex::Doorbell>),
+}
+
+// The futex word points into the mapping owned by the same Inner.
+unsafe impl Send for Signal {}
+unsafe impl Sync for Signal {}
+
+impl Signal {
+    fn futex(mmap: &Mapping) -> (Signal, Signal) {
+        let (e, f) = unsafe { crate::ringbuf::futex_words(mmap.as_mut_ptr()) };
+        (Signal::Futex(e), Signal::Futex(f))
+    }
+
+    /// The eventfd to pass to the other side, or to wait for in an event loop.
+    fn file(&self) -> Result<&File, std::io::Error> {
+        match self {
+            Signal::Eventfd(f) => Ok(f),
+            Signal::Futex(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Ringbuffer uses futex wakeups, there is no file descriptor")),
+            // Draining the eventfd from outside would steal wakeups from the other half.
+            Signal::Shared(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Ringbuffer is half of a Duplex, its file descriptor is shared")),
+        }
+    }
+
+    fn notify(&self) -> Result<(), std::io::Error> {
+        match self {
+            Signal::Eventfd(f) => signal(f),
+            Signal::Shared(d) => d.notify(),
+            Signal::Futex(w) => {
+                let w = unsafe { &**w };
+                w.fetch_add(1, Ordering::SeqCst);
+                futex_wake(w)
+            }
+        }
+    }
+
+    /// Returns the value to pass to "wait". This must be called before checking whether
+    /// to wait, so that a notification in between is not lost.
+    fn prepare_wait(&self) -> u32 {
+        match self {
+            Signal::Eventfd(_) => 0,
+            Signal::Futex(w) => unsafe { &**w }.load(Ordering::SeqCst),
+            Signal::Shared(d) => d.prepare_wait(),
+        }
+    }
+
+    /// Sleeps until woken up, or until the deadline has passed, in which case false is returned.
+    /// An eventfd signal also wakes up when "interrupt" is readable.
+    ///
+    /// Might return early for other reasons too, so the caller needs to check the buffer again.
+    fn wait(&self, val: u32, deadline: Option<Instant>, interrupt: Option<&File>) -> Result<bool, std::io::Error> {
+        let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        match self {
+            Signal::Eventfd(f) => {
+                if timeout.is_none() && interrupt.is_none() {
+                    wait(f)?;
+                    return Ok(true);
+                }
+                // A negative fd is ignored by poll.
+                let mut fds = [pollfd(f.as_raw_fd()), pollfd(interrupt.map_or(-1, |i| i.as_raw_fd()))];
+                if !poll(&mut fds, timeout)? {
+                    return Ok(false);
+                }
+                if fds[0].revents & libc::POLLIN != 0 {
+                    wait(f)?;
+                }
+                Ok(true)
+            }
+            Signal::Futex(w) => futex_wait(unsafe { &**w }, val, timeout),
+            Signal::Shared(d) => d.wait(val, deadline),
+        }
+    }
+}
+
+/// How far to spin in `WaitStrategy` before yielding.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Spin {
+    /// Do not spin at all.
+    None,
+    /// Check the buffer this many times, calling `std::hint::spin_loop` in between.
+    Iterations(u32),
+    /// Check the buffer, calling `std::hint::spin_loop` in between, until this much time has passed.
+    Duration(std::time::Duration),
+}
+
+/// How `block_until_readable` and `block_until_writable` wait for the other side.
+///
+/// Waiting happens in three phases: first busy-spinning, then calling `std::thread::yield_now`
+/// "yields" times, and finally sleeping on the signal until the other side wakes us up.
+/// Spinning and yielding avoid the wakeup latency of sleeping, at the cost of burning CPU.
+/// The default is to go to sleep right away.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WaitStrategy {
+    pub spin: Spin,
+    pub yields: u32,
+}
+
+impl Default for WaitStrategy {
+    fn default() -> Self { WaitStrategy { spin: Spin::None, yields: 0 } }
+}
+
+/// Counts in which phase of the `WaitStrategy` waits ended.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WaitStats {
+    /// Waits that returned right away, without waiting
+    pub ready: u64,
+    /// Waits that ended while spinning
+    pub spun: u64,
+    /// Waits that ended while yielding
+    pub yielded: u64,
+    /// Waits that went on to sleep on the signal
+    pub slept: u64,
+}
+
+/// Cancels the blocking waits of a `Sender` or `Receiver` from another thread.
+///
+/// Get one from `Sender::interrupter` or `Receiver::interrupter`, and clone it as needed.
+#[derive(Clone, Debug)]
+pub struct Interrupter(Arc<Interrupt>);
+
+#[derive(Debug)]
+struct Interrupt {
+    eventfd: File,
+    /// A duplicate of the signal that is waited for, to wake up event loops waiting for it.
+    signal: File,
+    interrupted: AtomicBool,
+}
+
+impl Interrupter {
+    /// Makes a blocked `block_until_readable` or `block_until_writable` return `Error::Cancelled`.
+    ///
+    /// This cannot be undone: all waits from now on return `Error::Cancelled` too, so that a
+    /// worker thread stops even if it was not blocked at the time. The same goes for the
+    /// tokio, futures and mio integrations: the signal is written to, so that the event loop
+    /// wakes up, and the next wait (or `drain_signal`) returns `Error::Cancelled`.
+    pub fn interrupt(&self) -> Result<(), Error> {
+        self.0.interrupted.store(true, Ordering::SeqCst);
+        // The eventfd is never read, so it stays readable.
+        signal(&self.0.eventfd)?;
+        Ok(signal(&self.0.signal)?)
+    }
+}
+
+/// The `Selector`s and mio registries a signal is registered with.
+#[derive(Default)]
+struct Registered {
+    /// Number of registrations. These wait for the signal without telling us, so while there
+    /// are any, the flag stays set.
+    count: AtomicUsize,
+    /// Set when the ringbuffer is dropped, so that selectors can remove it.
+    dropped: AtomicBool,
+}
+
+struct Waiter {
+    strategy: WaitStrategy,
+    stats: WaitStats,
+    /// What we last told the other side through "set_waiting". Anything changing the flag must
+    /// go through `start_waiting` and `stop_waiting`, or we might not set it before sleeping.
+    waiting: bool,
+    /// Shared with the `Selector`s the signal is registered with.
+    registered: Arc<Registered>,
+    interrupt: Option<Arc<Interrupt>>,
+    #[cfg(feature = "futures")]
+    registration: futures::Registration,
+}
+
+impl Default for Waiter {
+    // Attaching to a ringbuffer sets the waiting flag.
+    fn default() -> Self {
+        Waiter {
+            strategy: Default::default(),
+            stats: Default::default(),
+            waiting: true,
+            registered: Default::default(),
+            interrupt: None,
+            #[cfg(feature = "futures")]
+            registration: Default::default(),
+        }
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) { self.registered.dropped.store(true, Ordering::SeqCst); }
+}
+
+impl Waiter {
+    /// Ringbuffers set up with futex wakeups cannot be interrupted, since we cannot wait for
+    /// a futex and an eventfd at the same time. Neither can the halves of a `Duplex`, since only
+    /// one of them at a time waits for the eventfd.
+    fn interrupter(&mut self, signal: &Signal) -> Result<Interrupter, Error> {
+        let signal = signal.file()?;
+        let i = match &self.interrupt {
+            Some(i) => i.clone(),
+            None => {
+                let i = Interrupt { eventfd: eventfd()?, signal: signal.try_clone()?, interrupted: AtomicBool::new(false) };
+                self.interrupt.insert(Arc::new(i)).clone()
+            }
+        };
+        Ok(Interrupter(i))
+    }
+
+    fn check_interrupt(&self) -> Result<(), Error> {
+        match &self.interrupt {
+            Some(i) if i.interrupted.load(Ordering::SeqCst) => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits, according to the strategy, until "count" returns a non-zero number.
+    ///
+    /// "set_waiting" tells the other side whether we need to be signalled: we only do so
+    /// before sleeping, so that the other side can skip signalling us while we are busy.
+    /// Returns `Error::Timeout` if the deadline passes first, and `Error::Cancelled` if interrupted.
+    fn block<F, W>(&mut self, signal: &Signal, deadline: Option<Instant>, count: F, set_waiting: W) -> Result<usize, Error>
+    where
+        F: FnMut() -> Result<usize, Error>,
+        W: Fn(bool),
+    {
+        let s = self.phases(signal, deadline, count, &set_waiting)?;
+        self.stop_waiting(set_waiting);
+        Ok(s)
+    }
+
+    /// Tells the other side that we need to be signalled, unless we already did.
+    ///
+    /// The buffer must be checked after this and before waiting for the signal, so that we
+    /// either see what the other side has written, or it sees the flag.
+    fn start_waiting<W: Fn(bool)>(&mut self, set_waiting: W) {
+        if !self.waiting {
+            set_waiting(true);
+            self.waiting = true;
+        }
+    }
+
+    /// Tells the other side that it can skip signalling us, unless we already did, or the
+    /// signal is registered somewhere.
+    fn stop_waiting<W: Fn(bool)>(&mut self, set_waiting: W) {
+        if self.waiting && self.registered.count.load(Ordering::SeqCst) == 0 {
+            set_waiting(false);
+            self.waiting = false;
+        }
+    }
+
+    /// For a `Selector` or mio registry, which waits for the signal without telling us.
+    ///
+    /// The other side must always signal until the matching `unregister`, so the flag is set
+    /// here, and `stop_waiting` leaves it set until then.
+    fn register<W: Fn(bool)>(&self, set_waiting: W) {
+        self.registered.count.fetch_add(1, Ordering::SeqCst);
+        set_waiting(true);
+    }
+
+    #[cfg(feature = "mio")]
+    fn unregister(&self) { self.registered.count.fetch_sub(1, Ordering::SeqCst); }
+
+    fn phases<F, W>(&mut self, signal: &Signal, deadline: Option<Instant>, mut count: F, set_waiting: W) -> Result<usize, Error>
+    where
+        F: FnMut() -> Result<usize, Error>,
+        W: Fn(bool),
+    {
+        self.check_interrupt()?;
+        let s = count()?;
+        if s > 0 {
+            self.stats.ready += 1;
+            return Ok(s);
+        }
+        match self.strategy.spin {
+            Spin::None => {}
+            Spin::Iterations(n) => {
+                for _ in 0..n {
+                    self.check_interrupt()?;
+                    std::hint::spin_loop();
+                    let s = count()?;
+                    if s > 0 {
+                        self.stats.spun += 1;
+                        return Ok(s);
+                    }
+                }
+            }
+            Spin::Duration(d) => {
+                let mut end = Instant::now() + d;
+                if let Some(deadline) = deadline {
+                    end = std::cmp::min(end, deadline);
+                }
+                while Instant::now() < end {
+                    self.check_interrupt()?;
+                    std::hint::spin_loop();
+                    let s = count()?;
+                    if s > 0 {
+                        self.stats.spun += 1;
+                        return Ok(s);
+                    }
+                }
+            }
+        }
+        for _ in 0..self.strategy.yields {
+            self.check_interrupt()?;
+            std::thread::yield_now();
+            let s = count()?;
+            if s > 0 {
+                self.stats.yielded += 1;
+                return Ok(s);
+            }
+        }
+        self.stats.slept += 1;
+        self.start_waiting(set_waiting);
+        loop {
+            let val = signal.prepare_wait();
+            let s = count()?;
+            if s > 0 {
+                return Ok(s);
+            };
+            self.check_interrupt()?;
+            if !signal.wait(val, deadline, self.interrupt.as_ref().map(|i| &i.eventfd))? {
+                Err(Error::Timeout)?
+            }
+        }
+    }
+}
 
 struct Inner {
-    mmap: memmap2::MmapRaw,
+    // Dropped first, since it might refer to the signals.
+    waiter: Waiter,
+    mmap: Mapping,
     memfd: memfd::Memfd,
-    empty_signal: File,
-    full_signal: File,
+    empty_signal: Signal,
+    full_signal: Signal,
 }
 
-fn page_size() -> usize { unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize } }
-
-fn round_to_page_size<T>(capacity: usize) -> usize {
-    let bytes = crate::ringbuf::channel_bufsize::<T>(capacity);
-    let ps = page_size();
+fn round_to_page_size(bytes: usize) -> usize {
+    let ps = crate::mem::page_size();
     let m = bytes % ps;
     if m == 0 {
         bytes
@@ -35,6 +419,101 @@ fn round_to_page_size<T>(capacity: usize) -> usize {
     }
 }
 
+fn signal(f: &File) -> Result<(), std::io::Error> {
+    let mut f = f;
+    f.write_all(&1u64.to_ne_bytes())
+}
+
+/// Reads the eventfd, sleeping until the other side has written to it.
+///
+/// Being interrupted by a signal counts as a wakeup, the caller needs to check the buffer again anyway.
+fn wait(f: &File) -> Result<(), std::io::Error> {
+    let mut f = f;
+    let mut b = [0u8; 8];
+    match f.read(&mut b) {
+        Err(e) if e.kind() != std::io::ErrorKind::Interrupted => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Sleeps until the futex word is woken up, unless it no longer contains "val".
+///
+/// The word is in memory shared with an untrusted process, which might change it at any time.
+/// This only makes us return early, and the caller checks the buffer again anyway.
+///
+/// Returns false if "timeout" passed first.
+fn futex_wait(word: &AtomicU32, val: u32, timeout: Option<Duration>) -> Result<bool, std::io::Error> {
+    let ts = timeout.map(timespec);
+    let tsp = ts.as_ref().map_or(std::ptr::null(), |ts| ts as *const libc::timespec);
+    let r = unsafe { libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAIT, val, tsp) };
+    if r == -1 {
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EINTR) => {}
+            Some(libc::ETIMEDOUT) => return Ok(false),
+            _ => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn timespec(d: Duration) -> libc::timespec {
+    libc::timespec { tv_sec: d.as_secs() as libc::time_t, tv_nsec: d.subsec_nanos() as libc::c_long }
+}
+
+fn pollfd(fd: std::os::unix::io::RawFd) -> libc::pollfd { libc::pollfd { fd, events: libc::POLLIN, revents: 0 } }
+
+/// Waits for any of "fds" to become readable, returning false if "timeout" passed first.
+///
+/// Being interrupted by a signal counts as a wakeup, with no fd readable: the caller needs to
+/// check the buffer again anyway.
+fn poll(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> Result<bool, std::io::Error> {
+    let ts = timeout.map(timespec);
+    let tsp = ts.as_ref().map_or(std::ptr::null(), |ts| ts as *const libc::timespec);
+    let r = unsafe { libc::ppoll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, tsp, std::ptr::null()) };
+    if r == -1 {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EINTR) {
+            return Ok(true);
+        }
+        return Err(e);
+    }
+    Ok(r > 0)
+}
+
+/// Returns true if the eventfd becomes readable before "timeout" has passed.
+fn poll_readable(f: &File, timeout: Duration) -> Result<bool, std::io::Error> {
+    let mut fds = [pollfd(f.as_raw_fd())];
+    poll(&mut fds, Some(timeout))?;
+    Ok(fds[0].revents & libc::POLLIN != 0)
+}
+
+fn futex_wake(word: &AtomicU32) -> Result<(), std::io::Error> {
+    let r = unsafe { libc::syscall(libc::SYS_futex, word as *const AtomicU32, libc::FUTEX_WAKE, 1) };
+    if r == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes an eventfd non-blocking.
+///
+/// This changes the open file description, which is shared with the other side, but the other
+/// side only writes to it, and writing to an eventfd does not block in practice.
+fn set_nonblocking(f: &File, nonblocking: bool) -> Result<(), std::io::Error> {
+    let fd = f.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let flags = if nonblocking { flags | libc::O_NONBLOCK } else { flags & !libc::O_NONBLOCK };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 fn eventfd() -> Result<File, std::io::Error> {
     let x = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
     if x == -1 {
@@ -44,65 +523,191 @@ fn eventfd() -> Result<File, std::io::Error> {
     }
 }
 
+fn create_memfd<T>(bytes: usize, tlbsize: Option<HugetlbSize>) -> Result<memfd::Memfd, Error> {
+    let mut opts = MemfdOptions::default().allow_sealing(true).close_on_exec(true);
+    if tlbsize.is_some() {
+        opts = opts.hugetlb(tlbsize);
+    }
+
+    let memfd = opts.create(std::any::type_name::<T>())?;
+    if tlbsize.is_none() {
+        // hugetlb does not need/allow to set_len
+        memfd.as_file().set_len(bytes as u64)?;
+    }
+    Ok(memfd)
+}
+
+/// Returns header size, data size and capacity (in items) for a mirrored ringbuffer.
+///
+/// Both the header and the data need to be whole pages, and the data needs to hold a whole
+/// number of items, so the capacity might be rounded up.
+fn mirrored_layout<T>(capacity: usize) -> (usize, usize, usize) {
+    let ps = crate::mem::page_size();
+    let mut data = round_to_page_size(capacity * std::mem::size_of::<T>());
+    while data % std::mem::size_of::<T>() != 0 {
+        data += ps;
+    }
+    (ps, data, data / std::mem::size_of::<T>())
+}
+
 impl Inner {
-    fn new<T>(capacity: usize, tlbsize: Option<HugetlbSize>) -> Result<Self, Error> {
-        let bytes = round_to_page_size::<T>(capacity);
-        let mut opts = MemfdOptions::default().allow_sealing(true).close_on_exec(true);
-        if tlbsize.is_some() {
-            opts = opts.hugetlb(tlbsize);
-        }
+    fn new<T>(bufsize: usize, tlbsize: Option<HugetlbSize>) -> Result<Self, Error> {
+        let bytes = round_to_page_size(bufsize);
+        let memfd = create_memfd::<T>(bytes, tlbsize)?;
+        let empty_signal = Signal::Eventfd(eventfd()?);
+        let full_signal = Signal::Eventfd(eventfd()?);
+        let mmap = Mapping::Raw(crate::mem::raw_memfd(&memfd, bytes)?);
+        Ok(Self { mmap, memfd, empty_signal, full_signal, waiter: Waiter::default() })
+    }
 
-        let memfd = opts.create(std::any::type_name::<T>())?;
-        if tlbsize.is_none() {
-            // hugetlb does not need/allow to set_len
-            memfd.as_file().set_len(bytes as u64)?;
-        }
+    /// Like "new", but the sides wake each other up through futex words in the header.
+    fn new_futex<T>(bufsize: usize) -> Result<Self, Error> {
+        let bytes = round_to_page_size(bufsize);
+        let memfd = create_memfd::<T>(bytes, None)?;
+        let mmap = Mapping::Raw(crate::mem::raw_memfd(&memfd, bytes)?);
+        let (empty_signal, full_signal) = Signal::futex(&mmap);
+        Ok(Self { mmap, memfd, empty_signal, full_signal, waiter: Waiter::default() })
+    }
 
-        let empty_signal = eventfd()?;
-        let full_signal = eventfd()?;
-        let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
-        Ok(Self { mmap, memfd, empty_signal, full_signal })
+    fn new_mirrored<T>(capacity: usize) -> Result<(Self, usize, usize), Error> {
+        let (header, data, capacity) = mirrored_layout::<T>(capacity);
+        let memfd = create_memfd::<T>(header + data, None)?;
+        let empty_signal = Signal::Eventfd(eventfd()?);
+        let full_signal = Signal::Eventfd(eventfd()?);
+        let mmap = Mapping::Mirrored(crate::mem::raw_mirrored_memfd(&memfd, header, data)?);
+        let fingerprint = crate::ringbuf::type_fingerprint::<T>();
+        unsafe { crate::ringbuf::init_mirrored::<T>(mmap.as_mut_ptr(), header, capacity, fingerprint)? };
+        Ok((Self { mmap, memfd, empty_signal, full_signal, waiter: Waiter::default() }, header, capacity))
+    }
+
+    fn open_mirrored<T>(capacity: usize, file: File, empty_signal: File, full_signal: File) -> Result<(Self, usize, usize), Error> {
+        let (header, data, capacity) = mirrored_layout::<T>(capacity);
+        let memfd = memfd::Memfd::try_from_file(file).map_err(|_| std::io::Error::last_os_error())?;
+        let mmap = Mapping::Mirrored(crate::mem::raw_mirrored_memfd(&memfd, header, data)?);
+        let (empty_signal, full_signal) = (Signal::Eventfd(empty_signal), Signal::Eventfd(full_signal));
+        Ok((Self { mmap, memfd, empty_signal, full_signal, waiter: Waiter::default() }, header, capacity))
+    }
+
+    /// Writes the header for a ringbuffer of T, see `ringbuf::init`.
+    ///
+    /// The mapping is rounded up to whole pages, but the ringbuffer only uses "bufsize" bytes
+    /// of it, so that its capacity is exactly what was asked for.
+    fn init<T>(&self, bufsize: usize) -> Result<(), Error> {
+        let fingerprint = crate::ringbuf::type_fingerprint::<T>();
+        unsafe { crate::ringbuf::init::<T>(self.mmap.as_mut_ptr(), bufsize, fingerprint)? };
+        Ok(())
     }
 
     fn mlock(&mut self) -> Result<(), Error> { Ok(self.mmap.lock()?) }
 
-    fn open<T>(capacity: usize, file: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
-        let bytes = round_to_page_size::<T>(capacity);
+    fn open(bufsize: usize, file: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
+        let (memfd, mmap) = Self::map(bufsize, file)?;
+        let (empty_signal, full_signal) = (Signal::Eventfd(empty_signal), Signal::Eventfd(full_signal));
+        Ok(Self { mmap, memfd, empty_signal, full_signal, waiter: Waiter::default() })
+    }
+
+    fn open_futex(bufsize: usize, file: File) -> Result<Self, Error> {
+        let (memfd, mmap) = Self::map(bufsize, file)?;
+        let (empty_signal, full_signal) = Signal::futex(&mmap);
+        Ok(Self { mmap, memfd, empty_signal, full_signal, waiter: Waiter::default() })
+    }
+
+    fn map(bufsize: usize, file: File) -> Result<(memfd::Memfd, Mapping), Error> {
+        let bytes = round_to_page_size(bufsize);
         let memfd = memfd::Memfd::try_from_file(file).map_err(|_| std::io::Error::last_os_error())?;
         let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
-        if mmap.len() < bytes {
+        // Touching the mapping beyond the end of the file would crash us.
+        if mmap.len() < bytes || memfd.as_file().metadata()?.len() < bytes as u64 {
             Err(crate::ringbuf::Error::BufTooSmall)?
         };
-        Ok(Self { mmap, memfd, empty_signal, full_signal })
+        Ok((memfd, Mapping::Raw(mmap)))
     }
 }
 
-pub struct Sender<T>(Inner, crate::ringbuf::Sender<T>);
+pub struct Sender<T>(Inner, crate::ringbuf::Sender<T>, #[cfg(feature = "futures")] futures::Batch<T>);
 
 impl<T: Copy + zerocopy::AsBytes> Sender<T> {
+    #[cfg(feature = "futures")]
+    fn from_parts(inner: Inner, ringbuf: crate::ringbuf::Sender<T>) -> Self { Self(inner, ringbuf, Default::default()) }
+
+    #[cfg(not(feature = "futures"))]
+    fn from_parts(inner: Inner, ringbuf: crate::ringbuf::Sender<T>) -> Self { Self(inner, ringbuf) }
+
     /// Sets up a new ringbuffer and returns the sender half.
     pub fn new(capacity: usize) -> Result<Self, Error> {
-        let inner = Inner::new::<T>(capacity, None)?;
-        let ringbuf = unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::new::<T>(len, None)?;
+        inner.init::<T>(len)?;
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
+    }
+
+    /// Sets up a new ringbuffer that uses futex wakeups, and returns the sender half.
+    ///
+    /// Instead of eventfds, the sides wake each other up through futex words in the shared memory,
+    /// so only the memfd needs to be passed to the receiving side, which attaches using
+    /// `Receiver::open_futex`.
+    pub fn new_futex(capacity: usize) -> Result<Self, Error> {
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::new_futex::<T>(len)?;
+        inner.init::<T>(len)?;
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
+    }
+
+    /// Attaches to a ringbuffer set up by the receiving side with `new_futex`.
+    pub fn open_futex(capacity: usize, memfd: File) -> Result<Self, Error> {
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::open_futex(len, memfd)?;
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
+    }
+
+    /// Sets up a new ringbuffer where the data is mapped twice, back to back, and returns the sender half.
+    ///
+    /// This way all available items can always be accessed contiguously, i e, the closures
+    /// sent to the send and receive functions are called with everything available at once.
+    /// The capacity is rounded up to a whole number of pages.
+    pub fn new_mirrored(capacity: usize) -> Result<Self, Error> {
+        let (inner, header, capacity) = Inner::new_mirrored::<T>(capacity)?;
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach_mirrored(inner.mmap.as_mut_ptr(), header, capacity)? };
+        Ok(Self::from_parts(inner, ringbuf))
+    }
+
+    /// Attaches to a ringbuffer set up by the receiving side with `new_mirrored`.
+    pub fn open_mirrored(capacity: usize, memfd: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
+        let (inner, header, capacity) = Inner::open_mirrored::<T>(capacity, memfd, empty_signal, full_signal)?;
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach_mirrored(inner.mmap.as_mut_ptr(), header, capacity)? };
+        Ok(Self::from_parts(inner, ringbuf))
     }
 
     /// Create a new ringbuffer with hugetlb support and returns the sender half.
     /// Supports linux version 4.16+ only
     pub fn with_hugetlb(capacity: usize, tlbsize: HugetlbSize) -> Result<Self, Error> {
-        let inner = Inner::new::<T>(capacity, Some(tlbsize))?;
-        let ringbuf = unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::new::<T>(len, Some(tlbsize))?;
+        inner.init::<T>(len)?;
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
     }
 
     /// mlock the backing memory to avoid it being put into swap
     pub fn mlock(&mut self) -> Result<(), Error> { self.0.mlock() }
 
     /// Attaches to a ringbuffer set up by the receiving side.
+    ///
+    /// This can also be used to take over the ringbuffer from a sender that went away,
+    /// e g to restart a process without tearing down the ringbuffer. Sending continues where
+    /// the previous sender stopped.
+    ///
+    /// Fails if the header does not match T and "capacity", see `ringbuf::Error`. The type
+    /// fingerprint is only verified if the receiving side called `require_fingerprint`: it is
+    /// derived from the path of T, so it differs if the two sides define T in different crates.
     pub fn open(capacity: usize, memfd: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
-        let inner = Inner::open::<T>(capacity, memfd, empty_signal, full_signal)?;
-        let ringbuf = unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::open(len, memfd, empty_signal, full_signal)?;
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
     }
 
     /// Low-level access to the ringbuffer.
@@ -111,14 +716,56 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
     /// to wake up.
     pub fn sender_mut(&mut self) -> &mut crate::ringbuf::Sender<T> { &mut self.1 }
 
+    /// Checks that the other side set up the ringbuffer for the same item type.
+    ///
+    /// This compares `ringbuf::type_fingerprint`, so it only works if both sides are built
+    /// with the same compiler, and T has the same path on both sides. The size and alignment
+    /// of T are always verified when attaching.
+    pub fn check_fingerprint(&self) -> Result<(), Error> {
+        if self.1.fingerprint() != crate::ringbuf::type_fingerprint::<T>() {
+            Err(crate::ringbuf::Error::FingerprintMismatch)?
+        }
+        Ok(())
+    }
+
+    /// Makes `open` on the receiving side fail with `ringbuf::Error::FingerprintMismatch`,
+    /// unless its T has the same fingerprint as ours, see `check_fingerprint`.
+    ///
+    /// Call this right after setting up the ringbuffer, before passing it on.
+    pub fn require_fingerprint(&mut self) { self.1.require_fingerprint() }
+
     /// The file descriptor for the shared memory area
     pub fn memfd(&self) -> &memfd::Memfd { &self.0.memfd }
     /// The file descriptor written to when the receiving side should wake up
-    pub fn empty_signal(&self) -> &File { &self.0.empty_signal }
+    ///
+    /// # Panics
+    ///
+    /// If the ringbuffer was set up with futex wakeups, or is half of a `Duplex`.
+    pub fn empty_signal(&self) -> &File { self.try_empty_signal().expect("Ringbuffer has no empty signal file descriptor") }
     /// The file descriptor to register notification for in your favorite non-blocking framework (tokio, async-std etc).
     ///
-    /// It is written to by the receiving side when the buffer is no longer full.
-    pub fn full_signal(&self) -> &File { &self.0.full_signal }
+    /// It is written to by the receiving side when the buffer is no longer full, provided that
+    /// you have called `prepare_external_wait`.
+    ///
+    /// # Panics
+    ///
+    /// If the ringbuffer was set up with futex wakeups, or is half of a `Duplex`.
+    pub fn full_signal(&self) -> &File { self.try_full_signal().expect("Ringbuffer has no full signal file descriptor") }
+    /// Like `empty_signal`, but returns None instead of panicking.
+    pub fn try_empty_signal(&self) -> Option<&File> { self.0.empty_signal.file().ok() }
+    /// Like `full_signal`, but returns None instead of panicking.
+    pub fn try_full_signal(&self) -> Option<&File> { self.0.full_signal.file().ok() }
+
+    /// Tells the receiving side to always signal `full_signal`, until the next call to
+    /// `block_until_writable`, so that you can wait on it yourself.
+    ///
+    /// Returns the number of items that can be written. Only wait for the signal if this is
+    /// less than the write watermark, since the receiving side might have read everything already.
+    pub fn prepare_external_wait(&mut self) -> Result<usize, Error> {
+        let Sender(inner, ringbuf, ..) = self;
+        inner.waiter.start_waiting(|w| ringbuf.set_waiting(w));
+        Ok(ringbuf.write_count()?)
+    }
 
     /// Sends one or more items through the ringbuffer.
     ///
@@ -131,7 +778,53 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
     pub fn send_raw<F: FnOnce(*mut T, usize) -> usize>(&mut self, f: F) -> Result<Status, Error> {
         let status = self.sender_mut().send(f)?;
         if status.signal {
-            self.empty_signal().write(&1u64.to_ne_bytes())?;
+            self.0.empty_signal.notify()?;
+        }
+        Ok(status)
+    }
+
+    /// Copies as many items from the slice as there is room for into the ringbuffer.
+    ///
+    /// Returns the number of items sent. The receiving side is signalled at most once.
+    pub fn send_from(&mut self, items: &[T]) -> Result<usize, Error> {
+        let (n, status) = self.sender_mut().send_from(items)?;
+        if status.signal {
+            self.0.empty_signal.notify()?;
+        }
+        Ok(n)
+    }
+
+    /// Sends items from the iterator until either the iterator or the ringbuffer is exhausted.
+    ///
+    /// Items are only taken from the iterator if there is room for them, so you can pass
+    /// `&mut iter` and continue with the rest of the items later.
+    /// Returns the number of items sent. The receiving side is signalled at most once.
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, items: I) -> Result<usize, Error> {
+        let (n, status) = self.sender_mut().extend(items)?;
+        if status.signal {
+            self.0.empty_signal.notify()?;
+        }
+        Ok(n)
+    }
+
+    /// Reserves up to "max" items for writing.
+    ///
+    /// This is an alternative to `send_raw` that does not need a closure. The receiving side
+    /// is signalled, if needed, when the guard is committed.
+    pub fn reserve(&mut self, max: usize) -> Result<WriteGuard<'_, T>, Error> {
+        let Sender(inner, ringbuf, ..) = self;
+        Ok(WriteGuard { guard: ringbuf.reserve(max)?, signal: &inner.empty_signal })
+    }
+
+    /// Sends items through the ringbuffer, like `send_raw`, but the closure is only called
+    /// if at least "min" items can be written contiguously.
+    ///
+    /// If there is not enough room before the end of the buffer, the rest of the buffer is
+    /// wasted and writing continues at the start of the buffer.
+    pub fn send_raw_contiguous<F: FnOnce(*mut T, usize) -> usize>(&mut self, min: usize, f: F) -> Result<Status, Error> {
+        let status = self.sender_mut().send_contiguous(min, f)?;
+        if status.signal {
+            self.0.empty_signal.notify()?;
         }
         Ok(status)
     }
@@ -151,42 +844,132 @@ impl<T: Copy + zerocopy::AsBytes> Sender<T> {
         self.send_raw(|p, count| f(from_raw_parts_mut(p, count)))
     }
 
-    /// For blocking scenarios, blocks until the channel is writable.
-    pub fn block_until_writable(&mut self) -> Result<Status, Error> {
-        loop {
-            let s = self.sender_mut().write_count()?;
-            if s > 0 {
-                return Ok(Status { remaining: s, signal: false });
-            };
-            let mut b = [0u8; 8];
-            self.full_signal().read(&mut b)?;
-        }
+    /// For blocking scenarios, blocks until the channel is writable, i e, until at least
+    /// the write watermark number of items can be written.
+    ///
+    /// How to wait is decided by the `WaitStrategy`, see `set_wait_strategy`.
+    pub fn block_until_writable(&mut self) -> Result<Status, Error> { self.block_until_writable_inner(None) }
+
+    /// Like `block_until_writable`, but gives up with `Error::Timeout` after "timeout".
+    pub fn block_until_writable_timeout(&mut self, timeout: Duration) -> Result<Status, Error> {
+        self.block_until_writable_inner(Instant::now().checked_add(timeout))
+    }
+
+    /// Like `block_until_writable`, but gives up with `Error::Timeout` when "deadline" has passed.
+    pub fn block_until_writable_deadline(&mut self, deadline: Instant) -> Result<Status, Error> {
+        self.block_until_writable_inner(Some(deadline))
+    }
+
+    fn block_until_writable_inner(&mut self, deadline: Option<Instant>) -> Result<Status, Error> {
+        let Sender(inner, ringbuf, ..) = self;
+        let ringbuf = &*ringbuf;
+        let mark = ringbuf.write_watermark();
+        let count = || {
+            let s = ringbuf.write_count()?;
+            Ok(if s >= mark { s } else { 0 })
+        };
+        let s = inner.waiter.block(&inner.full_signal, deadline, count, |w| ringbuf.set_waiting(w))?;
+        Ok(Status { remaining: s, signal: false })
     }
+
+    /// Sets how `block_until_writable` waits for the receiving side.
+    pub fn set_wait_strategy(&mut self, strategy: WaitStrategy) { self.0.waiter.strategy = strategy }
+
+    /// How the calls to `block_until_writable` so far ended.
+    pub fn wait_stats(&self) -> WaitStats { self.0.waiter.stats }
+
+    /// Returns a handle for cancelling `block_until_writable` from another thread.
+    ///
+    /// Fails if the ringbuffer was set up with futex wakeups, or is half of a `Duplex`.
+    pub fn interrupter(&mut self) -> Result<Interrupter, Error> { self.0.waiter.interrupter(&self.0.full_signal) }
+
+    /// Sets how many items must be writable before the receiving side wakes us up.
+    ///
+    /// See `ringbuf::Sender::set_write_watermark`.
+    pub fn set_write_watermark(&mut self, items: usize) { self.1.set_write_watermark(items) }
 }
 
-pub struct Receiver<T>(Inner, crate::ringbuf::Receiver<T>);
+pub struct Receiver<T>(Inner, crate::ringbuf::Receiver<T>, #[cfg(feature = "futures")] futures::Batch<T>);
 
 impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
+    #[cfg(feature = "futures")]
+    fn from_parts(inner: Inner, ringbuf: crate::ringbuf::Receiver<T>) -> Self { Self(inner, ringbuf, Default::default()) }
+
+    #[cfg(not(feature = "futures"))]
+    fn from_parts(inner: Inner, ringbuf: crate::ringbuf::Receiver<T>) -> Self { Self(inner, ringbuf) }
+
     /// Sets up a new ringbuffer and returns the receiver half.
     pub fn new(capacity: usize) -> Result<Self, Error> {
-        let inner = Inner::new::<T>(capacity, None)?;
-        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::new::<T>(len, None)?;
+        inner.init::<T>(len)?;
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
+    }
+
+    /// Sets up a new ringbuffer that uses futex wakeups, and returns the receiver half.
+    ///
+    /// Instead of eventfds, the sides wake each other up through futex words in the shared memory,
+    /// so only the memfd needs to be passed to the sending side, which attaches using
+    /// `Sender::open_futex`.
+    pub fn new_futex(capacity: usize) -> Result<Self, Error> {
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::new_futex::<T>(len)?;
+        inner.init::<T>(len)?;
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
+    }
+
+    /// Attaches to a ringbuffer set up by the sending side with `new_futex`.
+    pub fn open_futex(capacity: usize, memfd: File) -> Result<Self, Error> {
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::open_futex(len, memfd)?;
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
+    }
+
+    /// Sets up a new ringbuffer where the data is mapped twice, back to back, and returns the receiver half.
+    ///
+    /// This way all available items can always be accessed contiguously, i e, the closures
+    /// sent to the send and receive functions are called with everything available at once.
+    /// The capacity is rounded up to a whole number of pages.
+    pub fn new_mirrored(capacity: usize) -> Result<Self, Error> {
+        let (inner, header, capacity) = Inner::new_mirrored::<T>(capacity)?;
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach_mirrored(inner.mmap.as_mut_ptr(), header, capacity)? };
+        Ok(Self::from_parts(inner, ringbuf))
+    }
+
+    /// Attaches to a ringbuffer set up by the sending side with `new_mirrored`.
+    pub fn open_mirrored(capacity: usize, memfd: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
+        let (inner, header, capacity) = Inner::open_mirrored::<T>(capacity, memfd, empty_signal, full_signal)?;
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach_mirrored(inner.mmap.as_mut_ptr(), header, capacity)? };
+        Ok(Self::from_parts(inner, ringbuf))
     }
 
     /// Create a new ringbuffer with hugetlb support and returns the receiver half.
     /// Supports linux version 4.16+ only
     pub fn with_hugetlb(capacity: usize, tlbsize: HugetlbSize) -> Result<Self, Error> {
-        let inner = Inner::new::<T>(capacity, Some(tlbsize))?;
-        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::new::<T>(len, Some(tlbsize))?;
+        inner.init::<T>(len)?;
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
     }
 
     /// Attaches to a ringbuffer set up by the sending side.
+    ///
+    /// This can also be used to take over the ringbuffer from a receiver that went away,
+    /// e g to restart a process without tearing down the ringbuffer. Receiving continues where
+    /// the previous receiver stopped.
+    ///
+    /// Fails if the header does not match T and "capacity", see `ringbuf::Error`. The type
+    /// fingerprint is only verified if the sending side called `require_fingerprint`, see
+    /// `Sender::open`.
     pub fn open(capacity: usize, memfd: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
-        let inner = Inner::open::<T>(capacity, memfd, empty_signal, full_signal)?;
-        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), inner.mmap.len())? };
-        Ok(Self(inner, ringbuf))
+        let len = crate::ringbuf::channel_bufsize::<T>(capacity);
+        let inner = Inner::open(len, memfd, empty_signal, full_signal)?;
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(inner.mmap.as_mut_ptr(), len)? };
+        Ok(Self::from_parts(inner, ringbuf))
     }
 
     /// mlock the backing memory to avoid it being put into swap
@@ -197,14 +980,56 @@ impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
     /// Note that reading directly using these methods will not trigger a signal for the sending side
     /// to wake up.
     pub fn receiver_mut(&mut self) -> &mut crate::ringbuf::Receiver<T> { &mut self.1 }
+
+    /// Checks that the other side set up the ringbuffer for the same item type.
+    ///
+    /// This compares `ringbuf::type_fingerprint`, so it only works if both sides are built
+    /// with the same compiler, and T has the same path on both sides. The size and alignment
+    /// of T are always verified when attaching.
+    pub fn check_fingerprint(&self) -> Result<(), Error> {
+        if self.1.fingerprint() != crate::ringbuf::type_fingerprint::<T>() {
+            Err(crate::ringbuf::Error::FingerprintMismatch)?
+        }
+        Ok(())
+    }
+
+    /// Makes `open` on the sending side fail with `ringbuf::Error::FingerprintMismatch`,
+    /// unless its T has the same fingerprint as ours, see `check_fingerprint`.
+    ///
+    /// Call this right after setting up the ringbuffer, before passing it on.
+    pub fn require_fingerprint(&mut self) { self.1.require_fingerprint() }
     /// The file descriptor for the shared memory area
     pub fn memfd(&self) -> &memfd::Memfd { &self.0.memfd }
     /// The file descriptor to register notification for in your favorite non-blocking framework (tokio, async-std etc).
     ///
-    /// It is written to by the sending side when the buffer is no longer empty.
-    pub fn empty_signal(&self) -> &File { &self.0.empty_signal }
+    /// It is written to by the sending side when the buffer is no longer empty, provided that
+    /// you have called `prepare_external_wait`.
+    ///
+    /// # Panics
+    ///
+    /// If the ringbuffer was set up with futex wakeups, or is half of a `Duplex`.
+    pub fn empty_signal(&self) -> &File { self.try_empty_signal().expect("Ringbuffer has no empty signal file descriptor") }
+
+    /// Tells the sending side to always signal `empty_signal`, until the next call to
+    /// `block_until_readable`, so that you can wait on it yourself.
+    ///
+    /// Returns the number of items that can be read. Only wait for the signal if this is
+    /// less than the read watermark, since the sending side might have written everything already.
+    pub fn prepare_external_wait(&mut self) -> Result<usize, Error> {
+        let Receiver(inner, ringbuf, ..) = self;
+        inner.waiter.start_waiting(|w| ringbuf.set_waiting(w));
+        Ok(ringbuf.read_count()?)
+    }
     /// The file descriptor written to when the sending side should wake up
-    pub fn full_signal(&self) -> &File { &self.0.full_signal }
+    ///
+    /// # Panics
+    ///
+    /// If the ringbuffer was set up with futex wakeups, or is half of a `Duplex`.
+    pub fn full_signal(&self) -> &File { self.try_full_signal().expect("Ringbuffer has no full signal file descriptor") }
+    /// Like `empty_signal`, but returns None instead of panicking.
+    pub fn try_empty_signal(&self) -> Option<&File> { self.0.empty_signal.file().ok() }
+    /// Like `full_signal`, but returns None instead of panicking.
+    pub fn try_full_signal(&self) -> Option<&File> { self.0.full_signal.file().ok() }
 
     /// Receives data from the ringbuffer.
     ///
@@ -217,7 +1042,45 @@ impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
     pub fn receive_raw<F: FnOnce(*const T, usize) -> usize>(&mut self, f: F) -> Result<Status, Error> {
         let status = self.receiver_mut().recv(f)?;
         if status.signal {
-            self.full_signal().write(&1u64.to_ne_bytes())?;
+            self.0.full_signal.notify()?;
+        }
+        Ok(status)
+    }
+
+    /// Copies as many items as are available, and fit, into the slice.
+    ///
+    /// Returns the number of items received. The sending side is signalled at most once.
+    pub fn recv_into(&mut self, items: &mut [T]) -> Result<usize, Error> {
+        let (n, status) = self.receiver_mut().recv_into(items)?;
+        if status.signal {
+            self.0.full_signal.notify()?;
+        }
+        Ok(n)
+    }
+
+    /// Returns an iterator that copies out the items that are currently available.
+    ///
+    /// The sending side is signalled at most once, when the iterator is dropped or finished.
+    pub fn drain(&mut self) -> Result<Drain<'_, T>, Error> {
+        let Receiver(inner, ringbuf, ..) = self;
+        Ok(Drain { drain: Some(ringbuf.drain()?), signal: &inner.full_signal })
+    }
+
+    /// Gives access to the items that can be read contiguously.
+    ///
+    /// This is an alternative to `receive_raw` that does not need a closure. The sending side
+    /// is signalled, if needed, when the guard is released.
+    pub fn peek(&mut self) -> Result<ReadGuard<'_, T>, Error> {
+        let Receiver(inner, ringbuf, ..) = self;
+        Ok(ReadGuard { guard: Some(ringbuf.peek()?), signal: &inner.full_signal })
+    }
+
+    /// Receives data from the ringbuffer, like `receive_raw`, but the closure is only called
+    /// if at least "min" items can be read contiguously.
+    pub fn receive_raw_contiguous<F: FnOnce(*const T, usize) -> usize>(&mut self, min: usize, f: F) -> Result<Status, Error> {
+        let status = self.receiver_mut().recv_contiguous(min, f)?;
+        if status.signal {
+            self.0.full_signal.notify()?;
         }
         Ok(status)
     }
@@ -237,15 +1100,744 @@ impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
         self.receive_raw(|p, count| f(from_raw_parts(p, count)))
     }
 
-    /// For blocking scenarios, blocks until the channel is readable.
-    pub fn block_until_readable(&mut self) -> Result<Status, Error> {
-        loop {
-            let s = self.receiver_mut().read_count()?;
+    /// For blocking scenarios, blocks until the channel is readable, i e, until at least
+    /// the read watermark number of items can be read.
+    ///
+    /// How to wait is decided by the `WaitStrategy`, see `set_wait_strategy`.
+    pub fn block_until_readable(&mut self) -> Result<Status, Error> { self.block_until_readable_inner(None) }
+
+    /// Like `block_until_readable`, but gives up with `Error::Timeout` after "timeout".
+    pub fn block_until_readable_timeout(&mut self, timeout: Duration) -> Result<Status, Error> {
+        self.block_until_readable_inner(Instant::now().checked_add(timeout))
+    }
+
+    /// Like `block_until_readable`, but gives up with `Error::Timeout` when "deadline" has passed.
+    pub fn block_until_readable_deadline(&mut self, deadline: Instant) -> Result<Status, Error> {
+        self.block_until_readable_inner(Some(deadline))
+    }
+
+    fn block_until_readable_inner(&mut self, deadline: Option<Instant>) -> Result<Status, Error> {
+        let Receiver(inner, ringbuf, ..) = self;
+        let ringbuf = &*ringbuf;
+        let mark = ringbuf.read_watermark();
+        let count = || {
+            let s = ringbuf.read_count()?;
+            Ok(if s >= mark { s } else { 0 })
+        };
+        let s = inner.waiter.block(&inner.empty_signal, deadline, count, |w| ringbuf.set_waiting(w))?;
+        Ok(Status { remaining: s, signal: false })
+    }
+
+    /// Sets how `block_until_readable` waits for the sending side.
+    pub fn set_wait_strategy(&mut self, strategy: WaitStrategy) { self.0.waiter.strategy = strategy }
+
+    /// How the calls to `block_until_readable` so far ended.
+    pub fn wait_stats(&self) -> WaitStats { self.0.waiter.stats }
+
+    /// Returns a handle for cancelling `block_until_readable` from another thread.
+    ///
+    /// Fails if the ringbuffer was set up with futex wakeups, or is half of a `Duplex`.
+    pub fn interrupter(&mut self) -> Result<Interrupter, Error> { self.0.waiter.interrupter(&self.0.empty_signal) }
+
+    /// Sets how many items must be readable before the sending side wakes us up.
+    ///
+    /// This is useful for streaming, where there is no point in waking up for less than
+    /// a period of data. See `ringbuf::Receiver::set_read_watermark`.
+    pub fn set_read_watermark(&mut self, items: usize) { self.1.set_read_watermark(items) }
+}
+
+/// Items reserved for writing, returned from `Sender::reserve`.
+///
+/// Because this is a ringbuffer between untrusted processes we can never create references to
+/// the data, so the items have to be written through the raw pointer.
+/// Nothing is sent until `commit` is called; dropping the guard leaves the buffer as it was.
+pub struct WriteGuard<'a, T> {
+    guard: crate::ringbuf::WriteGuard<'a, T>,
+    signal: &'a Signal,
+}
+
+impl<T: Copy + zerocopy::AsBytes> WriteGuard<'_, T> {
+    /// Pointer to the first reserved item, which can be written to using e g `std::ptr::write`.
+    pub fn as_mut_ptr(&self) -> *mut T { self.guard.as_mut_ptr() }
+
+    /// Number of reserved items.
+    pub fn len(&self) -> usize { self.guard.len() }
+
+    /// Returns true if no items could be reserved, i e, the buffer is full.
+    pub fn is_empty(&self) -> bool { self.guard.is_empty() }
+
+    /// Sends the first "count" reserved items, and wakes up the receiving side if needed.
+    pub fn commit(self, count: usize) -> Result<Status, Error> {
+        let status = self.guard.commit(count)?;
+        if status.signal {
+            self.signal.notify()?;
+        }
+        Ok(status)
+    }
+}
+
+/// Items available for reading, returned from `Receiver::peek`.
+///
+/// Because this is a ringbuffer between untrusted processes we can never create references to
+/// the data, so the items have to be read through the raw pointer.
+/// Nothing is removed from the buffer until `release` is called; dropping the guard leaves
+/// all items in the buffer.
+pub struct ReadGuard<'a, T: Copy + zerocopy::FromBytes> {
+    guard: Option<crate::ringbuf::ReadGuard<'a, T>>,
+    signal: &'a Signal,
+}
+
+impl<T: Copy + zerocopy::FromBytes> ReadGuard<'_, T> {
+    fn inner(&self) -> &crate::ringbuf::ReadGuard<'_, T> { self.guard.as_ref().unwrap() }
+
+    /// Pointer to the first available item, which can be read from using e g `std::ptr::read`.
+    pub fn as_ptr(&self) -> *const T { self.inner().as_ptr() }
+
+    /// Number of available items.
+    pub fn len(&self) -> usize { self.inner().len() }
+
+    /// Returns true if there are no available items, i e, the buffer is empty.
+    pub fn is_empty(&self) -> bool { self.inner().is_empty() }
+
+    /// Removes the first "count" available items from the buffer, and wakes up the sending side
+    /// if needed.
+    pub fn release(mut self, count: usize) -> Result<Status, Error> {
+        if count > self.len() {
+            Err(crate::ringbuf::Error::CallbackReadTooMuch)?
+        }
+        let status = self.guard.take().unwrap().release(count)?;
+        if status.signal {
+            self.signal.notify()?;
+        }
+        Ok(status)
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(guard) = self.guard.take() {
+            // Wasted items at the end of the buffer might have been skipped, which could free up space.
+            if let Ok(status) = guard.release(0) {
+                if status.signal {
+                    let _ = self.signal.notify();
+                }
+            }
+        }
+    }
+}
+
+/// Iterator returned from `Receiver::drain`.
+pub struct Drain<'a, T: Copy + zerocopy::FromBytes> {
+    drain: Option<crate::ringbuf::Drain<'a, T>>,
+    signal: &'a Signal,
+}
+
+impl<T: Copy + zerocopy::FromBytes> Drain<'_, T> {
+    /// Removes the items taken from the ringbuffer and wakes up the sending side if needed.
+    ///
+    /// An error is returned if the buffer was found to be corrupt during the iteration.
+    /// Dropping the iterator does the same thing, but ignores errors.
+    pub fn finish(mut self) -> Result<Status, Error> {
+        let status = self.drain.take().unwrap().finish()?;
+        if status.signal {
+            self.signal.notify()?;
+        }
+        Ok(status)
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Iterator for Drain<'_, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> { self.drain.as_mut().unwrap().next() }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.drain.as_ref().unwrap().size_hint() }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Drop for Drain<'_, T> {
+    fn drop(&mut self) {
+        if let Some(drain) = self.drain.take() {
+            if let Ok(status) = drain.finish() {
+                if status.signal {
+                    let _ = self.signal.notify();
+                }
+            }
+        }
+    }
+}
+
+/// Size of the header in front of every message, and the alignment of every message.
+const MSG_HEADER: usize = 8;
+/// Header value that tells the receiver to skip to the start of the buffer.
+const MSG_PADDING: u64 = u64::MAX;
+
+fn msg_size(len: usize) -> usize { MSG_HEADER + len.div_ceil(MSG_HEADER) * MSG_HEADER }
+
+fn check_msg_buf(length: usize) -> Result<(), Error> {
+    if length % MSG_HEADER != 0 {
+        Err(crate::ringbuf::Error::BufUnaligned)?
+    }
+    Ok(())
+}
+
+/// Sender half of a ringbuffer that transfers variable-sized messages.
+///
+/// Every message is written as a length header followed by the payload, padded to eight bytes.
+/// A message is always contiguous in memory: if it does not fit before the end of the buffer,
+/// the rest of the buffer is filled with padding and the message is written at the start.
+pub struct MessageSender(Sender<u8>);
+
+impl MessageSender {
+    /// Sets up a new ringbuffer of "capacity" bytes and returns the sender half.
+    ///
+    /// The capacity needs to be a multiple of eight.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        let s = Sender::new(capacity)?;
+        check_msg_buf(s.1.buf_len())?;
+        Ok(Self(s))
+    }
+
+    /// Attaches to a ringbuffer set up by the receiving side.
+    pub fn open(capacity: usize, memfd: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
+        let s = Sender::open(capacity, memfd, empty_signal, full_signal)?;
+        check_msg_buf(s.1.buf_len())?;
+        Ok(Self(s))
+    }
+
+    /// mlock the backing memory to avoid it being put into swap
+    pub fn mlock(&mut self) -> Result<(), Error> { self.0.mlock() }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd { self.0.memfd() }
+    /// The file descriptor written to when the receiving side should wake up
+    pub fn empty_signal(&self) -> &File { self.0.empty_signal() }
+    /// The file descriptor written to by the receiving side when the buffer is no longer full.
+    pub fn full_signal(&self) -> &File { self.0.full_signal() }
+
+    /// Sends a message through the ringbuffer.
+    ///
+    /// Returns false if there is currently not enough room in the buffer for the message,
+    /// and an error if the message can never fit.
+    pub fn send(&mut self, msg: &[u8]) -> Result<bool, Error> {
+        let need = msg_size(msg.len());
+        if need > self.0 .1.buf_len() {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let free = self.0 .1.write_count()?;
+        let empty = free == self.0 .1.buf_len();
+        if free < need {
+            return Ok(false);
+        }
+        let mut sent = false;
+        self.0.send_raw(|p, n| unsafe {
+            if n >= need {
+                std::ptr::write_unaligned(p as *mut u64, msg.len() as u64);
+                std::ptr::copy_nonoverlapping(msg.as_ptr(), p.add(MSG_HEADER), msg.len());
+                sent = true;
+                need
+            } else if free - n >= need || empty {
+                // Not enough room before the end of the buffer, so pad it and start over.
+                // If the buffer is empty, pad anyway: the message might fit at the start later.
+                std::ptr::write_unaligned(p as *mut u64, MSG_PADDING);
+                n
+            } else {
+                0
+            }
+        })?;
+        if sent {
+            return Ok(true);
+        }
+        self.0.send_raw(|p, n| unsafe {
+            if n < need {
+                return 0;
+            }
+            std::ptr::write_unaligned(p as *mut u64, msg.len() as u64);
+            std::ptr::copy_nonoverlapping(msg.as_ptr(), p.add(MSG_HEADER), msg.len());
+            sent = true;
+            need
+        })?;
+        Ok(sent)
+    }
+
+    /// For blocking scenarios, blocks until a message of "len" bytes can be sent.
+    ///
+    /// If the message does not fit before the end of the buffer, this waits for room for
+    /// padding the end too. If the message is larger than half the buffer, it might not fit
+    /// anywhere until the end has been padded: `send` then returns false, and this should be
+    /// called again.
+    pub fn block_until_writable(&mut self, len: usize) -> Result<Status, Error> { self.block_until_writable_inner(len, None) }
+
+    /// Like `block_until_writable`, but gives up with `Error::Timeout` after "timeout".
+    pub fn block_until_writable_timeout(&mut self, len: usize, timeout: Duration) -> Result<Status, Error> {
+        self.block_until_writable_inner(len, Instant::now().checked_add(timeout))
+    }
+
+    /// Like `block_until_writable`, but gives up with `Error::Timeout` when "deadline" has passed.
+    pub fn block_until_writable_deadline(&mut self, len: usize, deadline: Instant) -> Result<Status, Error> {
+        self.block_until_writable_inner(len, Some(deadline))
+    }
+
+    fn block_until_writable_inner(&mut self, len: usize, deadline: Option<Instant>) -> Result<Status, Error> {
+        let need = msg_size(len);
+        let l = self.0 .1.buf_len();
+        if need > l {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        }
+        let room = self.0 .1.contiguous_room();
+        let mark = if room >= need { need } else { std::cmp::min(room + need, l) };
+        // The receiving side wakes us up when this many bytes are free.
+        self.0.set_write_watermark(mark);
+        self.0.block_until_writable_inner(deadline)
+    }
+
+    /// Returns a handle for cancelling `block_until_writable` from another thread.
+    pub fn interrupter(&mut self) -> Result<Interrupter, Error> { self.0.interrupter() }
+}
+
+/// Receiver half of a ringbuffer that transfers variable-sized messages.
+///
+/// Message headers are written by an untrusted process, so a header with an invalid length
+/// causes `ringbuf::Error::MessageCorrupt` to be returned.
+pub struct MessageReceiver(Receiver<u8>);
+
+impl MessageReceiver {
+    /// Sets up a new ringbuffer of "capacity" bytes and returns the receiver half.
+    ///
+    /// The capacity needs to be a multiple of eight.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        let r = Receiver::new(capacity)?;
+        check_msg_buf(r.1.buf_len())?;
+        Ok(Self(r))
+    }
+
+    /// Attaches to a ringbuffer set up by the sending side.
+    pub fn open(capacity: usize, memfd: File, empty_signal: File, full_signal: File) -> Result<Self, Error> {
+        let r = Receiver::open(capacity, memfd, empty_signal, full_signal)?;
+        check_msg_buf(r.1.buf_len())?;
+        Ok(Self(r))
+    }
+
+    /// mlock the backing memory to avoid it being put into swap
+    pub fn mlock(&mut self) -> Result<(), Error> { self.0.mlock() }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd { self.0.memfd() }
+    /// The file descriptor written to by the sending side when the buffer is no longer empty.
+    pub fn empty_signal(&self) -> &File { self.0.empty_signal() }
+    /// The file descriptor written to when the sending side should wake up
+    pub fn full_signal(&self) -> &File { self.0.full_signal() }
+
+    /// Receives one message from the ringbuffer.
+    ///
+    /// The closure receives a (ptr, len) pair pointing to the message payload. As with
+    /// `Receiver::receive_raw`, the data can be changed by the other process at any time, so
+    /// it should be read using e g `std::ptr::read`. The message is dropped from the ringbuffer
+    /// after the closure returns.
+    /// Returns `None` if there is no message available.
+    pub fn receive_raw<R, F: FnOnce(*const u8, usize) -> R>(&mut self, f: F) -> Result<Option<R>, Error> {
+        let mut f = Some(f);
+        let mut r = None;
+        let mut corrupt = false;
+        let mut padding = false;
+        for _ in 0..2 {
+            self.0.receive_raw(|p, n| unsafe {
+                if n < MSG_HEADER {
+                    corrupt = true;
+                    return 0;
+                }
+                let header = std::ptr::read_unaligned(p as *const u64);
+                if header == MSG_PADDING {
+                    padding = true;
+                    return n;
+                }
+                if header > n as u64 || msg_size(header as usize) > n {
+                    corrupt = true;
+                    return 0;
+                }
+                let len = header as usize;
+                r = Some((f.take().unwrap())(p.add(MSG_HEADER), len));
+                msg_size(len)
+            })?;
+            if corrupt {
+                Err(crate::ringbuf::Error::MessageCorrupt)?
+            }
+            if !padding {
+                break;
+            }
+            padding = false;
+        }
+        Ok(r)
+    }
+
+    /// Receives one message from the ringbuffer and copies it into a `Vec`.
+    ///
+    /// Returns `None` if there is no message available.
+    pub fn receive(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        self.receive_raw(|p, len| {
+            let mut v = vec![0u8; len];
+            unsafe { std::ptr::copy_nonoverlapping(p, v.as_mut_ptr(), len) };
+            v
+        })
+    }
+
+    /// For blocking scenarios, blocks until the channel is readable.
+    pub fn block_until_readable(&mut self) -> Result<Status, Error> { self.0.block_until_readable() }
+
+    /// Like `block_until_readable`, but gives up with `Error::Timeout` after "timeout".
+    pub fn block_until_readable_timeout(&mut self, timeout: Duration) -> Result<Status, Error> {
+        self.0.block_until_readable_timeout(timeout)
+    }
+
+    /// Like `block_until_readable`, but gives up with `Error::Timeout` when "deadline" has passed.
+    pub fn block_until_readable_deadline(&mut self, deadline: Instant) -> Result<Status, Error> {
+        self.0.block_until_readable_deadline(deadline)
+    }
+
+    /// Returns a handle for cancelling `block_until_readable` from another thread.
+    pub fn interrupter(&mut self) -> Result<Interrupter, Error> { self.0.interrupter() }
+}
+
+/// Sender half of a ringbuffer with many senders and one receiver.
+///
+/// The receiving side sets up one memfd with a lane for every sender, see `MpscReceiver`.
+/// A sender writes to the ringbuffer in its own lane, and wakes up the receiving side through
+/// an eventfd shared by all senders. The receiving side wakes up a sender through a futex word
+/// in its lane, so a sender only needs the memfd and the empty signal.
+pub struct MpscSender<T>(Sender<T>);
+
+impl<T: Copy + zerocopy::AsBytes> MpscSender<T> {
+    /// Attaches to a lane set up by the receiving side with `MpscReceiver::add_sender`.
+    ///
+    /// "capacity" must be the same as the one the `MpscReceiver` was set up with.
+    pub fn open(capacity: usize, lane: usize, memfd: File, empty_signal: File) -> Result<Self, Error> {
+        let len = round_to_page_size(crate::ringbuf::channel_bufsize::<T>(capacity));
+        let start = lane.checked_mul(len).ok_or(crate::ringbuf::Error::BufTooBig)?;
+        let (memfd, mmap) = Inner::map(start + len, memfd)?;
+        let p = unsafe { mmap.as_mut_ptr().add(start) };
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach(p, crate::ringbuf::channel_bufsize::<T>(capacity))? };
+        let (_, full) = unsafe { crate::ringbuf::futex_words(p) };
+        let inner = Inner { mmap, memfd, empty_signal: Signal::Eventfd(empty_signal), full_signal: Signal::Futex(full), waiter: Waiter::default() };
+        Ok(Self(Sender::from_parts(inner, ringbuf)))
+    }
+
+    /// mlock the backing memory to avoid it being put into swap
+    pub fn mlock(&mut self) -> Result<(), Error> { self.0.mlock() }
+
+    /// Low-level access to the ringbuffer.
+    ///
+    /// Note that writing directly using these methods will not trigger a signal for the receiving side
+    /// to wake up.
+    pub fn sender_mut(&mut self) -> &mut crate::ringbuf::Sender<T> { self.0.sender_mut() }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd { self.0.memfd() }
+    /// The file descriptor written to when the receiving side should wake up
+    pub fn empty_signal(&self) -> &File { self.0.empty_signal() }
+
+    /// Sends as many items as there is room for, and returns the number of items sent.
+    pub fn send(&mut self, items: &[T]) -> Result<usize, Error> { self.0.send_from(items) }
+
+    /// For blocking scenarios, blocks until the channel is writable.
+    pub fn block_until_writable(&mut self) -> Result<Status, Error> { self.0.block_until_writable() }
+
+    /// Like `block_until_writable`, but gives up with `Error::Timeout` after "timeout".
+    pub fn block_until_writable_timeout(&mut self, timeout: Duration) -> Result<Status, Error> {
+        self.0.block_until_writable_timeout(timeout)
+    }
+}
+
+/// The ringbuffer of one sender in an `MpscReceiver`.
+struct Lane<T> {
+    ringbuf: crate::ringbuf::Receiver<T>,
+    full_signal: Signal,
+}
+
+/// Receiver half of a ringbuffer with many senders and one receiver.
+///
+/// All senders share one memfd, which is split into a fixed number of lanes, each starting at
+/// a page boundary and holding a ringbuffer of its own. Items are only ever read from the
+/// ringbuffer of a sender's own lane, and every lane is checked on its own, so a sender that
+/// corrupts its ringbuffer only gets its own lane removed.
+///
+/// Nothing stops a hostile sender from mapping all of the memfd though, and writing to the
+/// lanes of the other senders, or from writing to or reading from the shared empty signal.
+/// Senders that must not be able to disturb each other need an `MpscReceiver` each.
+pub struct MpscReceiver<T> {
+    // Dropped first, since it might refer to the signals.
+    waiter: Waiter,
+    /// The lane of every sender, indexed by the number returned from `add_sender`.
+    lanes: Vec<Option<Lane<T>>>,
+    mmap: memmap2::MmapRaw,
+    memfd: memfd::Memfd,
+    empty_signal: Signal,
+    capacity: usize,
+    /// Where the next `receive` starts, so that a busy sender cannot starve the others.
+    next_lane: usize,
+}
+
+impl<T: Copy + zerocopy::FromBytes> MpscReceiver<T> {
+    /// Sets up a memfd with room for "senders" senders, that can send "capacity" items each.
+    pub fn new(capacity: usize, senders: usize) -> Result<Self, Error> { Self::with_tlbsize(capacity, senders, None) }
+
+    /// Like `new`, but the memfd is set up with hugetlb support.
+    /// Supports linux version 4.16+ only
+    pub fn with_hugetlb(capacity: usize, senders: usize, tlbsize: HugetlbSize) -> Result<Self, Error> {
+        Self::with_tlbsize(capacity, senders, Some(tlbsize))
+    }
+
+    fn with_tlbsize(capacity: usize, senders: usize, tlbsize: Option<HugetlbSize>) -> Result<Self, Error> {
+        let len = round_to_page_size(crate::ringbuf::channel_bufsize::<T>(capacity));
+        let bytes = len.checked_mul(senders).ok_or(crate::ringbuf::Error::BufTooBig)?;
+        let memfd = create_memfd::<T>(bytes, tlbsize)?;
+        let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
+        let lanes = (0..senders).map(|_| None).collect();
+        let empty_signal = Signal::Eventfd(eventfd()?);
+        Ok(MpscReceiver { waiter: Waiter::default(), lanes, mmap, memfd, empty_signal, capacity, next_lane: 0 })
+    }
+
+    /// Sets up the ringbuffer in a free lane, for a new sender.
+    ///
+    /// Returns the lane, for `remove_sender`, and the memfd and empty signal file descriptors.
+    /// These should be transferred to the sending side and passed to `MpscSender::open`.
+    /// Fails if all lanes are taken.
+    pub fn add_sender(&mut self) -> Result<(usize, File, File), Error> {
+        let lane = self.lanes.iter().position(|l| l.is_none());
+        let lane = lane.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "All lanes are taken"))?;
+        let len = crate::ringbuf::channel_bufsize::<T>(self.capacity);
+        let p = unsafe { self.mmap.as_mut_ptr().add(lane * round_to_page_size(len)) };
+        let fingerprint = crate::ringbuf::type_fingerprint::<T>();
+        unsafe { crate::ringbuf::init::<T>(p, len, fingerprint)? };
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(p, len)? };
+        // The other lanes have the flag as we last set it.
+        ringbuf.set_waiting(self.waiter.waiting);
+        let (_, full) = unsafe { crate::ringbuf::futex_words(p) };
+        let files = (self.memfd.as_file().try_clone()?, self.empty_signal().try_clone()?);
+        self.lanes[lane] = Some(Lane { ringbuf, full_signal: Signal::Futex(full) });
+        Ok((lane, files.0, files.1))
+    }
+
+    /// Frees the lane of a sender, returning false if it was not taken.
+    ///
+    /// Items the sender has sent, but that have not been received, are lost. The lane is
+    /// reused by the next `add_sender`, so make sure the sender has gone away first.
+    pub fn remove_sender(&mut self, lane: usize) -> bool { self.lanes.get_mut(lane).and_then(Option::take).is_some() }
+
+    /// Returns true if the lane is taken by a sender that has not been removed.
+    pub fn has_sender(&self, lane: usize) -> bool { matches!(self.lanes.get(lane), Some(Some(_))) }
+
+    /// mlock the backing memory of all lanes to avoid it being put into swap
+    pub fn mlock(&mut self) -> Result<(), Error> { Ok(self.mmap.lock()?) }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd { &self.memfd }
+
+    /// The file descriptor written to by the sending sides when a buffer is no longer empty.
+    pub fn empty_signal(&self) -> &File { self.empty_signal.file().expect("Always an eventfd") }
+
+    /// Receives as many items as are available and fit into "items", and returns the number
+    /// of items received.
+    ///
+    /// If the ringbuffer of a sender turns out to be corrupt, that sender is removed and the
+    /// error is returned. The other senders are not affected; see `has_sender`.
+    pub fn receive(&mut self, items: &mut [T]) -> Result<usize, Error> {
+        let mut n = 0;
+        let lanes = self.lanes.len();
+        for i in 0..lanes {
+            if n == items.len() {
+                break;
+            }
+            let idx = (self.next_lane + i) % lanes;
+            let lane = match &mut self.lanes[idx] {
+                Some(lane) => lane,
+                None => continue,
+            };
+            let r = lane.ringbuf.recv_into(&mut items[n..]).map_err(Error::from).and_then(|(x, status)| {
+                if status.signal {
+                    lane.full_signal.notify()?;
+                }
+                Ok(x)
+            });
+            match r {
+                Ok(x) => n += x,
+                Err(e) => {
+                    self.lanes[idx] = None;
+                    return Err(e);
+                }
+            }
+        }
+        self.next_lane = if lanes == 0 { 0 } else { (self.next_lane + 1) % lanes };
+        Ok(n)
+    }
+
+    /// Returns the number of items that can be received from all senders together.
+    pub fn read_count(&self) -> Result<usize, Error> {
+        Ok(self.lanes.iter().flatten().map(|l| l.ringbuf.read_count()).sum::<Result<usize, _>>()?)
+    }
+
+    /// For blocking scenarios, blocks until any of the senders' ringbuffers is readable.
+    ///
+    /// How to wait is decided by the `WaitStrategy`, see `set_wait_strategy`.
+    pub fn block_until_readable(&mut self) -> Result<Status, Error> { self.block_until_readable_inner(None) }
+
+    /// Like `block_until_readable`, but gives up with `Error::Timeout` after "timeout".
+    pub fn block_until_readable_timeout(&mut self, timeout: Duration) -> Result<Status, Error> {
+        self.block_until_readable_inner(Instant::now().checked_add(timeout))
+    }
+
+    /// Like `block_until_readable`, but gives up with `Error::Timeout` when "deadline" has passed.
+    pub fn block_until_readable_deadline(&mut self, deadline: Instant) -> Result<Status, Error> {
+        self.block_until_readable_inner(Some(deadline))
+    }
+
+    fn block_until_readable_inner(&mut self, deadline: Option<Instant>) -> Result<Status, Error> {
+        let MpscReceiver { waiter, lanes, empty_signal, .. } = self;
+        let lanes = &*lanes;
+        // A corrupt lane counts as readable, so that `receive` gets to remove it.
+        let count = || Ok(lanes.iter().flatten().map(|l| l.ringbuf.read_count().unwrap_or(1)).sum());
+        let set_waiting = |w| lanes.iter().flatten().for_each(|l| l.ringbuf.set_waiting(w));
+        let s = waiter.block(empty_signal, deadline, count, set_waiting)?;
+        Ok(Status { remaining: s, signal: false })
+    }
+
+    /// Sets how `block_until_readable` waits for the sending sides.
+    pub fn set_wait_strategy(&mut self, strategy: WaitStrategy) { self.waiter.strategy = strategy }
+
+    /// How the calls to `block_until_readable` so far ended.
+    pub fn wait_stats(&self) -> WaitStats { self.waiter.stats }
+
+    /// Returns a handle for cancelling `block_until_readable` from another thread.
+    pub fn interrupter(&mut self) -> Result<Interrupter, Error> { self.waiter.interrupter(&self.empty_signal) }
+}
+
+/// Sender half of a ringbuffer with one sender and many receivers, where every receiver sees
+/// every item.
+///
+/// The sender never waits for receivers: when the buffer is full, the oldest items are overwritten.
+/// Every receiver gets its own empty signal file descriptor from `subscribe`, and receivers can only
+/// map the memfd read-only, so a hostile receiver cannot disturb the others.
+pub struct BroadcastSender<T> {
+    mmap: memmap2::MmapRaw,
+    memfd: memfd::Memfd,
+    /// The empty signal of every receiver, with the id returned from `subscribe`.
+    empty_signals: Vec<(usize, File)>,
+    next_id: usize,
+    ringbuf: crate::ringbuf::broadcast::Sender<T>,
+}
+
+impl<T: Copy + zerocopy::AsBytes> BroadcastSender<T> {
+    /// Sets up a new ringbuffer and returns the sender half.
+    ///
+    /// Supports linux version 5.1+ only, because the memfd is sealed against new writable mappings.
+    pub fn new(capacity: usize) -> Result<Self, Error> {
+        let bytes = round_to_page_size(crate::ringbuf::broadcast::channel_bufsize::<T>(capacity));
+        let memfd = create_memfd::<T>(bytes, None)?;
+        let mmap = crate::mem::raw_memfd(&memfd, bytes)?;
+        crate::mem::seal_future_write(&memfd)?;
+        let ringbuf = unsafe { crate::ringbuf::broadcast::Sender::attach(mmap.as_mut_ptr(), mmap.len())? };
+        Ok(Self { mmap, memfd, empty_signals: vec![], next_id: 0, ringbuf })
+    }
+
+    /// mlock the backing memory to avoid it being put into swap
+    pub fn mlock(&mut self) -> Result<(), Error> { Ok(self.mmap.lock()?) }
+
+    /// Low-level access to the ringbuffer.
+    ///
+    /// Note that writing directly using these methods will not trigger a signal for the receiving sides
+    /// to wake up.
+    pub fn sender_mut(&mut self) -> &mut crate::ringbuf::broadcast::Sender<T> { &mut self.ringbuf }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd { &self.memfd }
+
+    /// Adds a new receiver.
+    ///
+    /// Returns an id for `unsubscribe`, and the memfd and empty signal file descriptors, that
+    /// should be transferred to the receiving side and passed to `BroadcastReceiver::open`.
+    pub fn subscribe(&mut self) -> Result<(usize, File, File), Error> {
+        let memfd = self.memfd.as_file().try_clone()?;
+        let empty_signal = eventfd()?;
+        // A receiver that never reads its eventfd, or fills up its counter, must not block us.
+        set_nonblocking(&empty_signal, true)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.empty_signals.push((id, empty_signal.try_clone()?));
+        Ok((id, memfd, empty_signal))
+    }
+
+    /// Stops signalling a receiver that has gone away, returning false if it was not subscribed.
+    pub fn unsubscribe(&mut self, id: usize) -> bool {
+        let len = self.empty_signals.len();
+        self.empty_signals.retain(|(i, _)| *i != id);
+        self.empty_signals.len() != len
+    }
+
+    /// Returns true if the receiver has not been unsubscribed.
+    pub fn is_subscribed(&self, id: usize) -> bool { self.empty_signals.iter().any(|(i, _)| *i == id) }
+
+    /// Sends items to all receivers, overwriting the oldest items if the buffer is full.
+    ///
+    /// Receivers whose empty signal cannot be written to are unsubscribed.
+    pub fn send(&mut self, items: &[T]) -> Result<Status, Error> {
+        let status = self.ringbuf.send(items);
+        if status.signal {
+            self.empty_signals.retain(|(_, e)| signal(e).is_ok());
+        }
+        Ok(status)
+    }
+}
+
+/// Receiver half of a ringbuffer with one sender and many receivers.
+pub struct BroadcastReceiver<T> {
+    _mmap: memmap2::Mmap,
+    memfd: memfd::Memfd,
+    empty_signal: File,
+    ringbuf: crate::ringbuf::broadcast::Receiver<T>,
+}
+
+impl<T: Copy + zerocopy::FromBytes> BroadcastReceiver<T> {
+    /// Attaches to a ringbuffer set up by the sending side, using the file descriptors
+    /// returned by `BroadcastSender::subscribe`.
+    ///
+    /// Only items sent after this call will be received.
+    pub fn open(capacity: usize, memfd: File, empty_signal: File) -> Result<Self, Error> {
+        let bytes = round_to_page_size(crate::ringbuf::broadcast::channel_bufsize::<T>(capacity));
+        let memfd = memfd::Memfd::try_from_file(memfd).map_err(|_| std::io::Error::last_os_error())?;
+        let mmap = crate::mem::read_raw_memfd(&memfd, bytes)?;
+        if mmap.len() < bytes {
+            Err(crate::ringbuf::Error::BufTooSmall)?
+        };
+        let ringbuf = unsafe { crate::ringbuf::broadcast::Receiver::attach(mmap.as_ptr(), mmap.len())? };
+        Ok(Self { _mmap: mmap, memfd, empty_signal, ringbuf })
+    }
+
+    /// Low-level access to the ringbuffer.
+    pub fn receiver_mut(&mut self) -> &mut crate::ringbuf::broadcast::Receiver<T> { &mut self.ringbuf }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd { &self.memfd }
+    /// The file descriptor to register notification for in your favorite non-blocking framework (tokio, async-std etc).
+    ///
+    /// It is written to by the sending side when new items have been sent.
+    /// It is non-blocking, so reading it fails with `WouldBlock` if there is nothing to read.
+    pub fn empty_signal(&self) -> &File { &self.empty_signal }
+
+    /// Receives items into "items". The returned status tells how many items were received,
+    /// and how many were lost because the sender overwrote them.
+    pub fn receive(&mut self, items: &mut [T]) -> Result<crate::ringbuf::broadcast::RecvStatus, Error> {
+        Ok(self.ringbuf.recv(items)?)
+    }
+
+    /// For blocking scenarios, blocks until the channel is readable.
+    pub fn block_until_readable(&mut self) -> Result<Status, Error> {
+        loop {
+            let s = self.ringbuf.read_count()?;
             if s > 0 {
                 return Ok(Status { remaining: s, signal: false });
             };
-            let mut b = [0u8; 8];
-            self.empty_signal().read(&mut b)?;
+            // The eventfd is non-blocking, see `BroadcastSender::subscribe`.
+            poll(&mut [pollfd(self.empty_signal.as_raw_fd())], None)?;
+            match wait(&self.empty_signal) {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                r => r?,
+            }
         }
     }
 }
@@ -260,3 +1852,567 @@ fn simple() {
     let mut r: Receiver<i32> = Receiver::open(1000, memfd, e, f).unwrap();
     assert_eq!(r.receiver_mut().read_count().unwrap(), 0);
 }
+
+#[test]
+fn messages() {
+    let mut s = MessageSender::new(4000).unwrap();
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let mut r = MessageReceiver::open(4000, memfd, e, f).unwrap();
+    assert_eq!(r.receive().unwrap(), None);
+    assert!(s.send(b"").unwrap());
+    assert_eq!(r.receive().unwrap(), Some(vec![]));
+
+    // Go around the buffer a few times to make sure we wrap correctly.
+    for i in 0..200usize {
+        let msg: Vec<u8> = (0..(i * 7) % 1500).map(|x| (x + i) as u8).collect();
+        assert!(s.send(&msg).unwrap());
+        assert!(s.send(&msg[..msg.len() / 2]).unwrap());
+        assert_eq!(r.receive().unwrap().unwrap(), msg);
+        assert_eq!(r.receive().unwrap().unwrap(), &msg[..msg.len() / 2]);
+    }
+    assert_eq!(r.receive().unwrap(), None);
+
+    // Fill the buffer up
+    let mut count = 0;
+    while s.send(&[5u8; 100]).unwrap() {
+        count += 1;
+    }
+    assert!(count > 10);
+    assert!(s.send(&[5u8; 10000]).is_err());
+    for _ in 0..count {
+        assert_eq!(r.receive_raw(|_, len| len).unwrap(), Some(100));
+    }
+    assert_eq!(r.receive().unwrap(), None);
+}
+
+#[test]
+fn message_block_until_writable() {
+    let mut s = MessageSender::new(4096).unwrap();
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let mut r = MessageReceiver::open(4096, memfd, e, f).unwrap();
+    // Two of these fill the buffer.
+    let half = s.0 .1.buf_len() / 2 - MSG_HEADER;
+    assert!(s.send(&vec![1u8; half]).unwrap());
+    assert!(s.send(&vec![2u8; half]).unwrap());
+    assert!(!s.send(&vec![3u8; half]).unwrap());
+    assert!(matches!(s.block_until_writable_timeout(half, Duration::from_millis(10)), Err(Error::Timeout)));
+
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(r.receive().unwrap().unwrap(), vec![1u8; half]);
+        r
+    });
+    s.block_until_writable(half).unwrap();
+    assert!(s.send(&vec![3u8; half]).unwrap());
+    let mut r = t.join().unwrap();
+    assert_eq!(r.receive().unwrap().unwrap(), vec![2u8; half]);
+    assert_eq!(r.receive().unwrap().unwrap(), vec![3u8; half]);
+
+    // Larger than half the buffer, so it does not fit until the end has been padded.
+    let big = vec![4u8; half + 100];
+    s.block_until_writable(big.len()).unwrap();
+    assert!(!s.send(&big).unwrap());
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(r.receive().unwrap(), None);
+        r
+    });
+    s.block_until_writable(big.len()).unwrap();
+    assert!(s.send(&big).unwrap());
+    assert_eq!(t.join().unwrap().receive().unwrap().unwrap(), big);
+}
+
+#[test]
+fn corrupt_message() {
+    let mut s: Sender<u8> = Sender::new(4000).unwrap();
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let mut r = MessageReceiver::open(4000, memfd, e, f).unwrap();
+    s.send_raw(|p, _| unsafe {
+        std::ptr::write_unaligned(p as *mut u64, 100);
+        16
+    })
+    .unwrap();
+    assert!(matches!(r.receive(), Err(Error::Ringbuf(crate::ringbuf::Error::MessageCorrupt))));
+}
+
+#[test]
+fn mpsc() {
+    let mut r: MpscReceiver<u64> = MpscReceiver::new(1000, 4).unwrap();
+    let senders: Vec<_> = (0..4u64)
+        .map(|i| {
+            let (lane, memfd, e) = r.add_sender().unwrap();
+            let mut s: MpscSender<u64> = MpscSender::open(1000, lane, memfd, e).unwrap();
+            std::thread::spawn(move || {
+                let items: Vec<u64> = (0..10000).map(|j| i << 32 | j).collect();
+                let mut x = 0;
+                while x < items.len() {
+                    s.block_until_writable().unwrap();
+                    x += s.send(&items[x..]).unwrap();
+                }
+            })
+        })
+        .collect();
+    assert!(r.add_sender().is_err());
+    let mut next = [0u64; 4];
+    let mut buf = [0u64; 100];
+    while next.iter().any(|&n| n < 10000) {
+        r.block_until_readable().unwrap();
+        let n = r.receive(&mut buf).unwrap();
+        for item in &buf[..n] {
+            let i = (item >> 32) as usize;
+            assert_eq!(item & 0xffffffff, next[i]);
+            next[i] += 1;
+        }
+    }
+    for s in senders {
+        s.join().unwrap();
+    }
+    assert!(matches!(r.block_until_readable_timeout(Duration::from_millis(10)), Err(Error::Timeout)));
+    let i = r.interrupter().unwrap();
+    i.interrupt().unwrap();
+    assert!(matches!(r.block_until_readable(), Err(Error::Cancelled)));
+}
+
+#[test]
+fn mpsc_hostile_sender() {
+    let mut r: MpscReceiver<u32> = MpscReceiver::new(1000, 2).unwrap();
+    let mut open = || {
+        let (lane, memfd, e) = r.add_sender().unwrap();
+        (lane, MpscSender::<u32>::open(1000, lane, memfd, e).unwrap())
+    };
+    let (bad, mut s1) = open();
+    let (good, mut s2) = open();
+    s1.send(&[1, 2]).unwrap();
+    s2.send(&[3, 4]).unwrap();
+
+    // The first sender scribbles all over the header of its lane, including the write position.
+    let mmap = crate::mem::raw_memfd(s1.memfd(), 4096).unwrap();
+    unsafe { std::ptr::write_bytes(mmap.as_mut_ptr(), 0xff, 4096) };
+    let mut buf = [0u32; 10];
+    let mut received = vec![];
+    while r.has_sender(bad) {
+        match r.receive(&mut buf) {
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(e) => assert!(matches!(e, Error::Ringbuf(_))),
+        }
+    }
+    let n = r.receive(&mut buf).unwrap();
+    received.extend_from_slice(&buf[..n]);
+    assert!(received.ends_with(&[3, 4]));
+    s2.send(&[5]).unwrap();
+    assert_eq!(r.block_until_readable().unwrap().remaining, 1);
+    assert_eq!(r.receive(&mut buf).unwrap(), 1);
+
+    // The lane of the removed sender is set up again for the next one.
+    let (lane, memfd, e) = r.add_sender().unwrap();
+    assert_eq!(lane, bad);
+    let mut s3 = MpscSender::<u32>::open(1000, lane, memfd, e).unwrap();
+    s3.send(&[6]).unwrap();
+    assert_eq!(r.read_count().unwrap(), 1);
+    assert!(r.remove_sender(good));
+    assert!(!r.remove_sender(good));
+}
+
+#[test]
+fn broadcast() {
+    let mut s: BroadcastSender<u32> = BroadcastSender::new(1000).unwrap();
+    let (id1, memfd, e) = s.subscribe().unwrap();
+    let mut r1: BroadcastReceiver<u32> = BroadcastReceiver::open(1000, memfd, e).unwrap();
+    // Receivers cannot write to the buffer.
+    assert!(crate::mem::raw_memfd(r1.memfd(), 4096).is_err());
+    s.send(&[1, 2, 3]).unwrap();
+    let (_, memfd, e) = s.subscribe().unwrap();
+    let mut r2: BroadcastReceiver<u32> = BroadcastReceiver::open(1000, memfd, e).unwrap();
+    s.send(&[4]).unwrap();
+
+    let mut out = [0u32; 10];
+    r1.block_until_readable().unwrap();
+    let status = r1.receive(&mut out).unwrap();
+    assert_eq!((status.received, status.lost), (4, 0));
+    assert_eq!(&out[..4], &[1, 2, 3, 4]);
+    r2.block_until_readable().unwrap();
+    assert_eq!(r2.receive(&mut out).unwrap().received, 1);
+    assert_eq!(out[0], 4);
+
+    let cap = s.sender_mut().buf_len();
+    let items: Vec<u32> = (0..(cap as u32 + 5)).collect();
+    s.send(&items).unwrap();
+    let status = r1.receive(&mut out).unwrap();
+    assert_eq!((status.received, status.lost), (10, 5));
+    assert_eq!(out[0], 5);
+
+    // A receiver that has gone away is no longer signalled.
+    drop(r1);
+    assert!(s.unsubscribe(id1));
+    assert!(!s.is_subscribed(id1));
+    assert!(!s.unsubscribe(id1));
+    s.send(&[6]).unwrap();
+
+    // A hostile receiver fills up its eventfd counter, so that writing to it would block.
+    let (id3, memfd, e) = s.subscribe().unwrap();
+    let mut r3: BroadcastReceiver<u32> = BroadcastReceiver::open(1000, memfd, e).unwrap();
+    let mut f = r3.empty_signal();
+    f.write_all(&(u64::MAX - 1).to_ne_bytes()).unwrap();
+    while r2.receive(&mut out).unwrap().received > 0 {}
+    s.send(&[7]).unwrap();
+    assert!(!s.is_subscribed(id3));
+    r2.block_until_readable().unwrap();
+    assert_eq!(r2.receive(&mut out).unwrap().received, 1);
+    assert_eq!(r3.receive(&mut out).unwrap().received, 1);
+}
+
+#[test]
+fn mirrored() {
+    let mut s: Sender<u32> = Sender::new_mirrored(1000).unwrap();
+    let cap = s.sender_mut().buf_len();
+    assert_eq!(cap * 4 % crate::mem::page_size(), 0);
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let mut r: Receiver<u32> = Receiver::open_mirrored(1000, memfd, e, f).unwrap();
+    assert_eq!(r.receiver_mut().buf_len(), cap);
+
+    let mut next = 0u32;
+    let mut expected = 0u32;
+    for _ in 0..10 {
+        // Everything that is free or available is always contiguous.
+        s.send_raw(|p, count| {
+            assert_eq!(count, cap);
+            for i in 0..(cap - 5) {
+                unsafe { *p.add(i) = next };
+                next += 1;
+            }
+            cap - 5
+        })
+        .unwrap();
+        r.receive_raw(|p, count| {
+            assert_eq!(count, cap - 5);
+            for i in 0..count {
+                assert_eq!(unsafe { *p.add(i) }, expected);
+                expected += 1;
+            }
+            count
+        })
+        .unwrap();
+    }
+    // Fill it up completely, from the middle of the buffer
+    s.send_raw(|_, count| {
+        assert_eq!(count, cap);
+        count
+    })
+    .unwrap();
+    r.receive_raw(|_, count| {
+        assert_eq!(count, cap);
+        count
+    })
+    .unwrap();
+}
+
+#[test]
+fn guards() {
+    let mut s: Sender<u16> = Sender::new(1000).unwrap();
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let mut r: Receiver<u16> = Receiver::open(1000, memfd, e, f).unwrap();
+
+    let g = s.reserve(10).unwrap();
+    assert_eq!(g.len(), 10);
+    for i in 0..5 {
+        unsafe { *g.as_mut_ptr().add(i) = i as u16 };
+    }
+    assert!(g.commit(5).unwrap().signal);
+    r.block_until_readable().unwrap();
+    let g = r.peek().unwrap();
+    assert_eq!(g.len(), 5);
+    assert_eq!(unsafe { *g.as_ptr().add(4) }, 4);
+    assert!(g.release(6).is_err());
+    let g = r.peek().unwrap();
+    assert_eq!(g.release(5).unwrap().remaining, 0);
+    assert!(r.peek().unwrap().is_empty());
+}
+
+#[test]
+fn copy_items() {
+    let mut s: Sender<u64> = Sender::new(1000).unwrap();
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let mut r: Receiver<u64> = Receiver::open(1000, memfd, e, f).unwrap();
+    let cap = s.sender_mut().buf_len();
+
+    let items: Vec<u64> = (0..(cap as u64 + 10)).collect();
+    assert_eq!(s.send_from(&items).unwrap(), cap);
+    assert_eq!(s.send_from(&items).unwrap(), 0);
+    let mut out = vec![0u64; 20];
+    assert_eq!(r.recv_into(&mut out).unwrap(), 20);
+    assert_eq!(out, &items[..20]);
+    // The buffer was full, so the sending side should have been signalled.
+    s.block_until_writable().unwrap();
+    assert_eq!(s.extend(items[cap..].iter().copied()).unwrap(), 10);
+    let mut drain = r.drain().unwrap();
+    assert_eq!(drain.next(), Some(20));
+    assert_eq!(drain.by_ref().count(), cap - 11);
+    assert_eq!(drain.finish().unwrap().remaining, 0);
+    assert_eq!(r.recv_into(&mut out).unwrap(), 0);
+}
+
+#[test]
+fn header_mismatch() {
+    use crate::ringbuf::Error as RError;
+    let r: Receiver<u64> = Receiver::new(1000).unwrap();
+    let open = |capacity| {
+        let memfd = r.memfd().as_file().try_clone().unwrap();
+        let e = r.empty_signal().try_clone().unwrap();
+        let f = r.full_signal().try_clone().unwrap();
+        (capacity, memfd, e, f)
+    };
+    let (c, m, e, f) = open(1000);
+    let s: Sender<u64> = Sender::open(c, m, e, f).unwrap();
+    s.check_fingerprint().unwrap();
+    let (c, m, e, f) = open(1000);
+    assert!(matches!(Sender::<i64>::open(c, m, e, f).unwrap().check_fingerprint(), Err(Error::Ringbuf(RError::FingerprintMismatch))));
+    let (c, m, e, f) = open(1000);
+    assert!(matches!(Sender::<u32>::open(c, m, e, f), Err(Error::Ringbuf(RError::ItemLayout))));
+    let (c, m, e, f) = open(100);
+    assert!(matches!(Sender::<u64>::open(c, m, e, f), Err(Error::Ringbuf(RError::CapacityMismatch))));
+    // The same number of pages, but not the same capacity.
+    let (c, m, e, f) = open(999);
+    assert!(matches!(Sender::<u64>::open(c, m, e, f), Err(Error::Ringbuf(RError::CapacityMismatch))));
+    assert_eq!(r.descriptor().unwrap().capacity(), 1000);
+
+    let mut r: Receiver<u64> = Receiver::new(1000).unwrap();
+    r.require_fingerprint();
+    let (m, e, f) = (r.memfd().as_file().try_clone().unwrap(), r.empty_signal().try_clone().unwrap(), r.full_signal().try_clone().unwrap());
+    assert!(matches!(Sender::<i64>::open(1000, m, e, f), Err(Error::Ringbuf(RError::FingerprintMismatch))));
+}
+
+#[test]
+fn reattach() {
+    let mut r: Receiver<u16> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let e = r.empty_signal().try_clone().unwrap();
+    let f = r.full_signal().try_clone().unwrap();
+    let mut s: Sender<u16> = Sender::open(1000, memfd, e, f).unwrap();
+    let items: Vec<u16> = (0..10).collect();
+    assert_eq!(s.send_from(&items).unwrap(), 10);
+    let mut out = [0u16; 4];
+    assert_eq!(r.recv_into(&mut out).unwrap(), 4);
+
+    // Restart the receiving side
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let e = r.empty_signal().try_clone().unwrap();
+    let f = r.full_signal().try_clone().unwrap();
+    drop(r);
+    let mut r: Receiver<u16> = Receiver::open(1000, memfd, e, f).unwrap();
+    assert_eq!(s.send_from(&items[..2]).unwrap(), 2);
+    r.block_until_readable().unwrap();
+    assert_eq!(r.drain().unwrap().collect::<Vec<_>>(), &[4, 5, 6, 7, 8, 9, 0, 1]);
+}
+
+#[test]
+fn futex() {
+    let mut r: Receiver<u32> = Receiver::new_futex(1000).unwrap();
+    assert!(r.try_empty_signal().is_none() && r.try_full_signal().is_none());
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let total = r.receiver_mut().buf_len() as u32 * 3;
+    // Someone scribbling over the futex word should not make us miss a wakeup.
+    unsafe { (*crate::ringbuf::futex_words(r.0.mmap.as_mut_ptr()).0).store(12345, Ordering::SeqCst) };
+    let t = std::thread::spawn(move || {
+        let mut s: Sender<u32> = Sender::open_futex(1000, memfd).unwrap();
+        for i in 0..total {
+            while s.send_from(&[i]).unwrap() == 0 {
+                s.block_until_writable().unwrap();
+            }
+        }
+    });
+    let mut expected = 0;
+    let mut out = [0u32; 100];
+    while expected < total {
+        r.block_until_readable().unwrap();
+        let n = r.recv_into(&mut out).unwrap();
+        for x in &out[..n] {
+            assert_eq!(*x, expected);
+            expected += 1;
+        }
+        std::thread::sleep(std::time::Duration::from_micros(50));
+    }
+    t.join().unwrap();
+}
+
+#[test]
+fn wait_strategy() {
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let e = r.empty_signal().try_clone().unwrap();
+    let f = r.full_signal().try_clone().unwrap();
+    let mut s: Sender<u32> = Sender::open(1000, memfd, e, f).unwrap();
+    s.block_until_writable().unwrap();
+    assert_eq!(s.wait_stats(), WaitStats { ready: 1, ..Default::default() });
+
+    let t = std::thread::spawn(move || {
+        for i in 0..3 {
+            std::thread::sleep(Duration::from_millis(10));
+            s.send_from(&[i]).unwrap();
+        }
+    });
+    let mut out = [0u32; 4];
+    let strategies = [
+        WaitStrategy { spin: Spin::Duration(Duration::from_secs(10)), yields: 0 },
+        WaitStrategy { spin: Spin::None, yields: u32::MAX },
+        WaitStrategy::default(),
+    ];
+    for strategy in &strategies {
+        r.set_wait_strategy(*strategy);
+        r.block_until_readable().unwrap();
+        assert_eq!(r.recv_into(&mut out).unwrap(), 1);
+    }
+    t.join().unwrap();
+    assert_eq!(r.wait_stats(), WaitStats { ready: 0, spun: 1, yielded: 1, slept: 1 });
+}
+
+#[test]
+fn timeouts() {
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let mut s: Sender<u32> = Sender::open(1000, memfd, r.empty_signal().try_clone().unwrap(), r.full_signal().try_clone().unwrap()).unwrap();
+    let start = Instant::now();
+    assert!(matches!(r.block_until_readable_timeout(Duration::from_millis(20)), Err(Error::Timeout)));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert!(matches!(r.block_until_readable_deadline(start), Err(Error::Timeout)));
+
+    let items = vec![7u32; s.sender_mut().buf_len()];
+    assert_eq!(s.send_from(&items).unwrap(), items.len());
+    assert!(matches!(s.block_until_writable_timeout(Duration::from_millis(1)), Err(Error::Timeout)));
+    assert_eq!(r.block_until_readable_timeout(Duration::from_secs(10)).unwrap().remaining, items.len());
+    assert_eq!(r.wait_stats().slept, 2);
+
+    // The same, with futex wakeups
+    let mut r: Receiver<u32> = Receiver::new_futex(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let mut s: Sender<u32> = Sender::open_futex(1000, memfd).unwrap();
+    assert!(matches!(r.block_until_readable_timeout(Duration::from_millis(1)), Err(Error::Timeout)));
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(10));
+        s.send_from(&[5]).unwrap();
+    });
+    assert_eq!(r.block_until_readable_timeout(Duration::from_secs(10)).unwrap().remaining, 1);
+    t.join().unwrap();
+}
+
+#[test]
+fn watermarks() {
+    let mut r: Receiver<u32> = Receiver::new_futex(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    r.set_read_watermark(50);
+    let t = std::thread::spawn(move || {
+        let mut s: Sender<u32> = Sender::open_futex(1000, memfd).unwrap();
+        for i in 0..60 {
+            s.send_from(&[i]).unwrap();
+            std::thread::sleep(Duration::from_micros(100));
+        }
+    });
+    assert!(r.block_until_readable().unwrap().remaining >= 50);
+    t.join().unwrap();
+    assert_eq!(r.wait_stats().slept, 1);
+}
+
+#[test]
+fn threads() {
+    // Small enough for the receiver to often read items before the sender is done committing them.
+    let mut s: Sender<u64> = Sender::new(4).unwrap();
+    let memfd = s.memfd().as_file().try_clone().unwrap();
+    let e = s.empty_signal().try_clone().unwrap();
+    let f = s.full_signal().try_clone().unwrap();
+    let mut r: Receiver<u64> = Receiver::open(4, memfd, e, f).unwrap();
+    const TOTAL: u64 = 1000000;
+    let t = std::thread::spawn(move || {
+        for i in 0..TOTAL {
+            s.block_until_writable().unwrap();
+            assert_eq!(s.send_from(&[i]).unwrap(), 1);
+        }
+    });
+    let mut next = 0;
+    while next < TOTAL {
+        // Times out rather than hangs if the sender fails.
+        r.block_until_readable_timeout(Duration::from_secs(10)).unwrap();
+        for item in r.drain().unwrap() {
+            assert_eq!(item, next);
+            next += 1;
+        }
+    }
+    t.join().unwrap();
+}
+
+#[test]
+fn skip_signal_when_busy() {
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let e = r.empty_signal().try_clone().unwrap();
+    let f = r.full_signal().try_clone().unwrap();
+    let mut s: Sender<u32> = Sender::open(1000, memfd, e, f).unwrap();
+    s.send_from(&[1]).unwrap();
+    wait(r.0.empty_signal.file().unwrap()).unwrap();
+    r.block_until_readable().unwrap();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+
+    // The receiver did not sleep, so it is not waiting for a signal.
+    s.send_from(&[2]).unwrap();
+    assert!(!poll_readable(r.0.empty_signal.file().unwrap(), Duration::ZERO).unwrap());
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+
+    // Until it waits for the signal itself.
+    assert_eq!(r.prepare_external_wait().unwrap(), 0);
+    s.send_from(&[3]).unwrap();
+    wait(r.empty_signal()).unwrap();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(10));
+        s.send_from(&[4]).unwrap();
+    });
+    r.block_until_readable().unwrap();
+    t.join().unwrap();
+    assert_eq!(r.wait_stats().slept, 1);
+}
+
+#[test]
+fn interrupter() {
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let mut s: Sender<u32> = Sender::open(1000, memfd, r.empty_signal().try_clone().unwrap(), r.full_signal().try_clone().unwrap()).unwrap();
+    let i = r.interrupter().unwrap();
+    s.send_from(&[1]).unwrap();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+
+    let t = std::thread::spawn(move || {
+        let e = r.block_until_readable().unwrap_err();
+        // Also when there is something to read
+        s.send_from(&[2]).unwrap();
+        assert!(matches!(r.block_until_readable_timeout(Duration::from_secs(10)), Err(Error::Cancelled)));
+        e
+    });
+    std::thread::sleep(Duration::from_millis(10));
+    i.clone().interrupt().unwrap();
+    assert!(matches!(t.join().unwrap(), Error::Cancelled));
+
+    // Also while spinning or yielding
+    for strategy in [WaitStrategy { spin: Spin::Iterations(u32::MAX), yields: 0 }, WaitStrategy { spin: Spin::None, yields: u32::MAX }] {
+        let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+        r.set_wait_strategy(strategy);
+        let i = r.interrupter().unwrap();
+        let t = std::thread::spawn(move || r.block_until_readable().map(|_| ()));
+        std::thread::sleep(Duration::from_millis(10));
+        i.interrupt().unwrap();
+        assert!(matches!(t.join().unwrap(), Err(Error::Cancelled)));
+    }
+
+    let mut r: Receiver<u32> = Receiver::new_futex(1000).unwrap();
+    assert!(r.interrupter().is_err());
+}
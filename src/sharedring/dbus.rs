@@ -0,0 +1,132 @@
+//! Exporting ringbuffers over D-Bus, and opening them.
+//!
+//! The ringbuffer is set up when a D-Bus method is called, and returned as the method's output
+//! arguments: capacity, memfd, empty signal and full signal, i e the signature "thhh".
+//! This signature has no room for a layout, so only ringbuffers set up with `new` are supported.
+
+use super::{Layout, Receiver, RingDescriptor, Sender};
+use crate::Error;
+use dbus::blocking::{BlockingSender, Proxy};
+use dbus_crossroads::{Context, IfaceBuilder, MethodDesc, MethodErr};
+use std::fs::File;
+
+/// The output arguments of a method added by `receiver_method` or `sender_method`.
+pub type SetupReply = (u64, File, File, File);
+
+fn failed(e: Error) -> MethodErr { MethodErr::failed(&e) }
+
+/// Fails for anything not set up with `new`, since the reply has no room for a layout.
+fn reply(d: RingDescriptor) -> Result<SetupReply, MethodErr> {
+    if d.layout() != Layout::Plain {
+        Err(MethodErr::failed("Only ringbuffers set up with new can be sent over D-Bus"))?
+    }
+    let capacity = d.capacity() as u64;
+    match d.into_files() {
+        (memfd, Some((e, f))) => Ok((capacity, memfd, e, f)),
+        (_, None) => Err(MethodErr::failed("Ringbuffer has no signals")),
+    }
+}
+
+/// Adds a method without input arguments to the interface. Every call sets up a ringbuffer of
+/// "capacity" items, passes the receiving half to "f", and returns the ringbuffer to the caller,
+/// who sends on it.
+///
+/// If "f" returns an error, the ringbuffer is dropped and the error is returned to the caller.
+pub fn receiver_method<D, T, N, F>(b: &mut IfaceBuilder<D>, name: N, capacity: usize, mut f: F) -> &mut MethodDesc
+where
+    D: Send + 'static,
+    T: Copy + zerocopy::FromBytes,
+    N: Into<dbus::strings::Member<'static>>,
+    F: FnMut(&mut Context, &mut D, Receiver<T>) -> Result<(), MethodErr> + Send + 'static,
+{
+    b.method(name, (), ("capacity", "memfd", "empty_signal", "full_signal"), move |ctx, data, (): ()| {
+        let r = Receiver::new(capacity).map_err(failed)?;
+        let d = reply(r.descriptor().map_err(failed)?)?;
+        f(ctx, data, r)?;
+        Ok(d)
+    })
+}
+
+/// Like `receiver_method`, but "f" gets the sending half, and the caller receives.
+pub fn sender_method<D, T, N, F>(b: &mut IfaceBuilder<D>, name: N, capacity: usize, mut f: F) -> &mut MethodDesc
+where
+    D: Send + 'static,
+    T: Copy + zerocopy::AsBytes,
+    N: Into<dbus::strings::Member<'static>>,
+    F: FnMut(&mut Context, &mut D, Sender<T>) -> Result<(), MethodErr> + Send + 'static,
+{
+    b.method(name, (), ("capacity", "memfd", "empty_signal", "full_signal"), move |ctx, data, (): ()| {
+        let s = Sender::new(capacity).map_err(failed)?;
+        let d = reply(s.descriptor().map_err(failed)?)?;
+        f(ctx, data, s)?;
+        Ok(d)
+    })
+}
+
+/// `Sender` or `Receiver`, for `open_via_dbus`.
+pub trait OpenDescriptor: Sized {
+    fn open_descriptor(descriptor: RingDescriptor) -> Result<Self, Error>;
+}
+
+impl<T: Copy + zerocopy::AsBytes> OpenDescriptor for Sender<T> {
+    fn open_descriptor(descriptor: RingDescriptor) -> Result<Self, Error> { Sender::open_descriptor(descriptor) }
+}
+
+impl<T: Copy + zerocopy::FromBytes> OpenDescriptor for Receiver<T> {
+    fn open_descriptor(descriptor: RingDescriptor) -> Result<Self, Error> { Receiver::open_descriptor(descriptor) }
+}
+
+/// Calls a method added by `receiver_method` (to get a `Sender`) or `sender_method` (to get
+/// a `Receiver`), and attaches to the returned ringbuffer.
+pub fn open_via_dbus<R, S, C>(proxy: &Proxy<'_, C>, interface: &str, method: &str) -> Result<R, Error>
+where
+    R: OpenDescriptor,
+    S: BlockingSender,
+    C: std::ops::Deref<Target = S>,
+{
+    let (capacity, memfd, e, f): SetupReply = proxy.method_call(interface, method, ())?;
+    let d = RingDescriptor::new(capacity as usize, Layout::Plain, memfd, Some((e, f)))?;
+    R::open_descriptor(d)
+}
+
+#[test]
+fn setup_method() {
+    use dbus::Message;
+    use std::cell::RefCell;
+
+    struct Replies(RefCell<Vec<Message>>);
+    impl dbus::channel::Sender for Replies {
+        fn send(&self, msg: Message) -> Result<u32, ()> {
+            self.0.borrow_mut().push(msg);
+            Ok(0)
+        }
+    }
+
+    let mut cr = dbus_crossroads::Crossroads::new();
+    let iface = cr.register("com.example.test", |b: &mut IfaceBuilder<Vec<Receiver<u32>>>| {
+        receiver_method(b, "Setup", 1000, |_, rings, r| {
+            rings.push(r);
+            Ok(())
+        });
+    });
+    cr.insert("/test", &[iface], vec![]);
+    let replies = Replies(RefCell::new(vec![]));
+    let mut msg = Message::new_method_call("com.example.test", "/test", "com.example.test", "Setup").unwrap();
+    msg.set_serial(1);
+    cr.handle_message(msg, &replies).unwrap();
+
+    let reply = replies.0.borrow_mut().pop().unwrap();
+    let (capacity, memfd, e, f): SetupReply = reply.read4().unwrap();
+    let mut s: Sender<u32> = Sender::open(capacity as usize, memfd, e, f).unwrap();
+    s.send_from(&[1, 2]).unwrap();
+    let rings: &mut Vec<Receiver<u32>> = cr.data_mut(&"/test".into()).unwrap();
+    assert_eq!(rings[0].recv_into(&mut [0; 4]).unwrap(), 2);
+}
+
+#[test]
+fn reply_layout() {
+    let r: Receiver<u32> = Receiver::new_futex(100).unwrap();
+    assert!(reply(r.descriptor().unwrap()).is_err());
+    let r: Receiver<u32> = Receiver::new(100).unwrap();
+    assert_eq!(reply(r.descriptor().unwrap()).unwrap().0, 100);
+}
@@ -0,0 +1,184 @@
+//! Transferring a ringbuffer to the other side as a whole.
+//!
+//! A `RingDescriptor` bundles the file descriptors with the capacity and layout, so that the
+//! other side does not need to know how the ringbuffer was set up to attach to it. The
+//! `handshake` module and the D-Bus setup both send one.
+
+use super::{Inner, Mapping, Receiver, Sender, Signal};
+use crate::Error;
+use std::fs::File;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+
+/// How a ringbuffer was set up, which decides how the other side attaches to it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Set up with `new` or `with_hugetlb`, attached to with `open`.
+    Plain,
+    /// Set up with `new_futex`, attached to with `open_futex`. There are no signal file descriptors.
+    Futex,
+    /// Set up with `new_mirrored`, attached to with `open_mirrored`.
+    Mirrored,
+}
+
+/// Everything the other side needs to attach to a ringbuffer: its capacity and layout, and
+/// the memfd and signal file descriptors.
+///
+/// Get one from `Sender::descriptor` or `Receiver::descriptor`, transfer it to the other
+/// side, and attach with `Sender::open_descriptor` or `Receiver::open_descriptor`.
+#[derive(Debug)]
+pub struct RingDescriptor {
+    capacity: usize,
+    layout: Layout,
+    memfd: File,
+    signals: Option<(File, File)>,
+}
+
+impl RingDescriptor {
+    /// Fails if there are signals for a `Layout::Futex` ringbuffer, or none for the others.
+    pub fn new(capacity: usize, layout: Layout, memfd: File, signals: Option<(File, File)>) -> Result<Self, Error> {
+        if signals.is_some() == (layout == Layout::Futex) {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Signals do not match the ringbuffer layout"))?
+        }
+        Ok(RingDescriptor { capacity, layout, memfd, signals })
+    }
+
+    /// Takes ownership of the file descriptors, in the order returned by `into_raw_fds`.
+    ///
+    /// If the number of file descriptors is wrong for the layout, they are all closed and an
+    /// error is returned.
+    ///
+    /// # Safety
+    ///
+    /// The file descriptors must be open, and not owned by anything else.
+    pub unsafe fn from_raw_fds(capacity: usize, layout: Layout, fds: &[RawFd]) -> Result<Self, Error> {
+        let mut files = fds.iter().map(|fd| File::from_raw_fd(*fd));
+        match (files.next(), files.next(), files.next(), files.next()) {
+            (Some(m), None, _, _) => Self::new(capacity, layout, m, None),
+            (Some(m), Some(e), Some(f), None) => Self::new(capacity, layout, m, Some((e, f))),
+            _ => {
+                files.for_each(drop);
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Wrong number of file descriptors"))?
+            }
+        }
+    }
+
+    /// Gives up ownership of the file descriptors: the memfd, then the empty and full signals
+    /// unless the layout is `Layout::Futex`.
+    pub fn into_raw_fds(self) -> Vec<RawFd> {
+        let (memfd, signals) = self.into_files();
+        let mut fds = vec![memfd.into_raw_fd()];
+        if let Some((e, f)) = signals {
+            fds.extend([e.into_raw_fd(), f.into_raw_fd()]);
+        }
+        fds
+    }
+
+    /// The memfd, and the empty and full signals unless the layout is `Layout::Futex`.
+    pub fn into_files(self) -> (File, Option<(File, File)>) { (self.memfd, self.signals) }
+
+    /// Number of items the ringbuffer holds.
+    pub fn capacity(&self) -> usize { self.capacity }
+
+    /// How the ringbuffer was set up.
+    pub fn layout(&self) -> Layout { self.layout }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &File { &self.memfd }
+
+    /// The empty and full signals, or `None` if the layout is `Layout::Futex`.
+    pub fn signals(&self) -> Option<(&File, &File)> { self.signals.as_ref().map(|(e, f)| (e, f)) }
+
+    /// Duplicates the file descriptors, e g to hand the same ringbuffer to another process.
+    pub fn try_clone(&self) -> Result<Self, Error> {
+        let signals = match &self.signals {
+            Some((e, f)) => Some((e.try_clone()?, f.try_clone()?)),
+            None => None,
+        };
+        Ok(RingDescriptor { capacity: self.capacity, layout: self.layout, memfd: self.memfd.try_clone()?, signals })
+    }
+}
+
+impl Inner {
+    fn descriptor(&self, capacity: usize) -> Result<RingDescriptor, Error> {
+        let memfd = self.memfd.as_file().try_clone()?;
+        let (layout, signals) = match (&self.mmap, &self.empty_signal, &self.full_signal) {
+            (_, Signal::Shared(_), _) | (_, _, Signal::Shared(_)) => {
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Duplex ringbuffers can only be transferred together"))?
+            }
+            (_, Signal::Futex(_), _) => (Layout::Futex, None),
+            (Mapping::Raw(_), Signal::Eventfd(e), Signal::Eventfd(f)) => (Layout::Plain, Some((e.try_clone()?, f.try_clone()?))),
+            (Mapping::Mirrored(_), Signal::Eventfd(e), Signal::Eventfd(f)) => (Layout::Mirrored, Some((e.try_clone()?, f.try_clone()?))),
+            (_, Signal::Eventfd(_), Signal::Futex(_)) => Err(Error::Handshake("Mixed eventfd and futex signals"))?,
+        };
+        Ok(RingDescriptor { capacity, layout, memfd, signals })
+    }
+}
+
+impl<T: Copy + zerocopy::AsBytes> Sender<T> {
+    /// Returns what the receiving side needs to attach to this ringbuffer, with the file
+    /// descriptors duplicated.
+    pub fn descriptor(&self) -> Result<RingDescriptor, Error> { self.0.descriptor(self.1.buf_len()) }
+
+    /// Attaches to a ringbuffer set up by the receiving side, in the way its layout requires.
+    pub fn open_descriptor(descriptor: RingDescriptor) -> Result<Self, Error> {
+        let RingDescriptor { capacity, layout, memfd, signals } = descriptor;
+        match (layout, signals) {
+            (Layout::Plain, Some((e, f))) => Self::open(capacity, memfd, e, f),
+            (Layout::Mirrored, Some((e, f))) => Self::open_mirrored(capacity, memfd, e, f),
+            (Layout::Futex, None) => Self::open_futex(capacity, memfd),
+            _ => Err(Error::Handshake("Signals do not match the ringbuffer layout"))?,
+        }
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
+    /// Returns what the sending side needs to attach to this ringbuffer, with the file
+    /// descriptors duplicated.
+    pub fn descriptor(&self) -> Result<RingDescriptor, Error> { self.0.descriptor(self.1.buf_len()) }
+
+    /// Attaches to a ringbuffer set up by the sending side, in the way its layout requires.
+    pub fn open_descriptor(descriptor: RingDescriptor) -> Result<Self, Error> {
+        let RingDescriptor { capacity, layout, memfd, signals } = descriptor;
+        match (layout, signals) {
+            (Layout::Plain, Some((e, f))) => Self::open(capacity, memfd, e, f),
+            (Layout::Mirrored, Some((e, f))) => Self::open_mirrored(capacity, memfd, e, f),
+            (Layout::Futex, None) => Self::open_futex(capacity, memfd),
+            _ => Err(Error::Handshake("Signals do not match the ringbuffer layout"))?,
+        }
+    }
+}
+
+#[test]
+fn descriptors() {
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let d = r.descriptor().unwrap();
+    assert_eq!((d.layout(), d.capacity()), (Layout::Plain, r.1.buf_len()));
+    let mut s: Sender<u32> = Sender::open_descriptor(d.try_clone().unwrap()).unwrap();
+    s.send_from(&[1, 2]).unwrap();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 2);
+
+    let (capacity, layout, fds) = (d.capacity(), d.layout(), d.into_raw_fds());
+    assert_eq!(fds.len(), 3);
+    let d = unsafe { RingDescriptor::from_raw_fds(capacity, layout, &fds) }.unwrap();
+    let mut s: Sender<u32> = Sender::open_descriptor(d).unwrap();
+    s.send_from(&[3]).unwrap();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+
+    let mut s: Sender<u32> = Sender::new_mirrored(1000).unwrap();
+    let d = s.descriptor().unwrap();
+    assert_eq!(d.layout(), Layout::Mirrored);
+    let mut r: Receiver<u32> = Receiver::open_descriptor(d).unwrap();
+    s.send_from(&[4]).unwrap();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+
+    let mut s: Sender<u32> = Sender::new_futex(1000).unwrap();
+    let d = s.descriptor().unwrap();
+    assert!(d.signals().is_none());
+    let memfd = d.memfd().try_clone().unwrap();
+    let mut r: Receiver<u32> = Receiver::open_descriptor(d).unwrap();
+    s.send_from(&[5]).unwrap();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+
+    let e = memfd.try_clone().unwrap();
+    assert!(RingDescriptor::new(1000, Layout::Futex, memfd, Some((e.try_clone().unwrap(), e))).is_err());
+}
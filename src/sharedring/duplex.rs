@@ -0,0 +1,213 @@
+//! Two ringbuffers in one memfd, one in each direction.
+//!
+//! The side calling `Duplex::new` sends on the first ringbuffer and receives on the second one,
+//! each starting at a page boundary. Instead of an empty and a full signal per ringbuffer,
+//! every side has a single eventfd, which the other side writes to whenever the first side
+//! should wake up, no matter for which ringbuffer.
+//!
+//! Both halves of one side might wait for the eventfd at the same time, from different threads.
+//! Reading it would then steal the wakeup from the other half, so only one of them reads it
+//! at a time, and tells the other one to check its ringbuffer again when done.
+
+use super::{create_memfd, eventfd, poll, pollfd, round_to_page_size, Inner, Receiver, Sender, Signal, Waiter};
+use crate::Error;
+use std::fs::File;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+/// The eventfd of one side, shared by both its halves.
+pub(super) struct Doorbell {
+    eventfd: File,
+    state: Mutex<State>,
+    turn: Condvar,
+}
+
+struct State {
+    /// One of the halves is waiting for the eventfd.
+    reading: bool,
+    /// Increased every time a half has stopped waiting for the eventfd.
+    generation: u32,
+}
+
+impl Doorbell {
+    fn new(eventfd: File) -> Self {
+        Doorbell { eventfd, state: Mutex::new(State { reading: false, generation: 0 }), turn: Condvar::new() }
+    }
+
+    pub(super) fn notify(&self) -> Result<(), std::io::Error> { super::signal(&self.eventfd) }
+
+    pub(super) fn prepare_wait(&self) -> u32 { self.state.lock().unwrap().generation }
+
+    /// Like `Signal::wait`: "val" is from `prepare_wait`, and if the other half has woken up
+    /// since, we return right away, since the wakeup might have been for us.
+    pub(super) fn wait(&self, val: u32, deadline: Option<Instant>) -> Result<bool, std::io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.generation != val {
+            return Ok(true);
+        }
+        if state.reading {
+            match deadline {
+                None => drop(self.turn.wait(state).unwrap()),
+                Some(d) => {
+                    let (state, r) = self.turn.wait_timeout(state, d.saturating_duration_since(Instant::now())).unwrap();
+                    if r.timed_out() && state.generation == val {
+                        return Ok(false);
+                    }
+                }
+            }
+            return Ok(true);
+        }
+        state.reading = true;
+        drop(state);
+
+        let r = match deadline {
+            None => super::wait(&self.eventfd).map(|_| true),
+            Some(d) => {
+                let mut fds = [pollfd(self.eventfd.as_raw_fd())];
+                match poll(&mut fds, Some(d.saturating_duration_since(Instant::now()))) {
+                    Ok(true) if fds[0].revents & libc::POLLIN != 0 => super::wait(&self.eventfd).map(|_| true),
+                    r => r,
+                }
+            }
+        };
+
+        // Whatever happened, the other half needs to check its ringbuffer again.
+        let mut state = self.state.lock().unwrap();
+        state.reading = false;
+        state.generation = state.generation.wrapping_add(1);
+        self.turn.notify_all();
+        r
+    }
+}
+
+/// Both directions of a request/response channel, set up in a single memfd.
+///
+/// The two directions can have different item types. The other side attaches with `open`,
+/// with "Tx" and "Rx" swapped. Use `split` to send and receive from different threads.
+pub struct Duplex<Tx, Rx> {
+    sender: Sender<Tx>,
+    receiver: Receiver<Rx>,
+    doorbell: Arc<Doorbell>,
+    peer_signal: File,
+}
+
+impl<Tx: Copy + zerocopy::AsBytes, Rx: Copy + zerocopy::FromBytes> Duplex<Tx, Rx> {
+    /// Sets up two new ringbuffers, for sending "tx_capacity" and receiving "rx_capacity" items.
+    pub fn new(tx_capacity: usize, rx_capacity: usize) -> Result<Self, Error> {
+        let (tx, rx) = (crate::ringbuf::channel_bufsize::<Tx>(tx_capacity), crate::ringbuf::channel_bufsize::<Rx>(rx_capacity));
+        let (tx_len, rx_len) = (round_to_page_size(tx), round_to_page_size(rx));
+        let memfd = create_memfd::<Self>(tx_len + rx_len, None)?;
+        Self::attach(memfd.into_file(), eventfd()?, eventfd()?, 0..tx, tx_len..tx_len + rx, true)
+    }
+
+    /// Attaches to ringbuffers set up by the other side.
+    ///
+    /// The capacities are the other way around compared to the other side's, and so are the
+    /// signals: "signal" is the other side's `peer_signal`, and vice versa.
+    pub fn open(tx_capacity: usize, rx_capacity: usize, memfd: File, signal: File, peer_signal: File) -> Result<Self, Error> {
+        let (tx, rx) = (crate::ringbuf::channel_bufsize::<Tx>(tx_capacity), crate::ringbuf::channel_bufsize::<Rx>(rx_capacity));
+        let rx_len = round_to_page_size(rx);
+        Self::attach(memfd, signal, peer_signal, rx_len..rx_len + tx, 0..rx, false)
+    }
+
+    fn attach(memfd: File, signal: File, peer_signal: File, tx: Range<usize>, rx: Range<usize>, init: bool) -> Result<Self, Error> {
+        let doorbell = Arc::new(Doorbell::new(signal));
+        let bytes = std::cmp::max(tx.end, rx.end);
+
+        // Every half maps all of the memfd, so that they can be dropped independently.
+        let (memfd_tx, mmap) = Inner::map(bytes, memfd.try_clone()?)?;
+        let p = mmap.as_mut_ptr();
+        if init {
+            let (ftx, frx) = (crate::ringbuf::type_fingerprint::<Tx>(), crate::ringbuf::type_fingerprint::<Rx>());
+            unsafe { crate::ringbuf::init::<Tx>(p.add(tx.start), tx.len(), ftx)? };
+            unsafe { crate::ringbuf::init::<Rx>(p.add(rx.start), rx.len(), frx)? };
+        }
+        let ringbuf = unsafe { crate::ringbuf::Sender::attach(p.add(tx.start), tx.len())? };
+        let inner = Inner {
+            mmap,
+            memfd: memfd_tx,
+            empty_signal: Signal::Eventfd(peer_signal.try_clone()?),
+            full_signal: Signal::Shared(doorbell.clone()),
+            waiter: Waiter::default(),
+        };
+        let sender = Sender::from_parts(inner, ringbuf);
+
+        let (memfd_rx, mmap) = Inner::map(bytes, memfd)?;
+        let ringbuf = unsafe { crate::ringbuf::Receiver::attach(mmap.as_mut_ptr().add(rx.start), rx.len())? };
+        let inner = Inner {
+            mmap,
+            memfd: memfd_rx,
+            empty_signal: Signal::Shared(doorbell.clone()),
+            full_signal: Signal::Eventfd(peer_signal.try_clone()?),
+            waiter: Waiter::default(),
+        };
+        let receiver = Receiver::from_parts(inner, ringbuf);
+        Ok(Duplex { sender, receiver, doorbell, peer_signal })
+    }
+
+    /// The half sending to the other side.
+    pub fn sender(&mut self) -> &mut Sender<Tx> { &mut self.sender }
+
+    /// The half receiving from the other side.
+    pub fn receiver(&mut self) -> &mut Receiver<Rx> { &mut self.receiver }
+
+    /// Separates the halves, e g to send and receive from different threads.
+    pub fn split(self) -> (Sender<Tx>, Receiver<Rx>) { (self.sender, self.receiver) }
+
+    /// Number of items that fit in the sending direction, to pass to the other side.
+    pub fn tx_capacity(&self) -> usize { self.sender.1.buf_len() }
+
+    /// Number of items that fit in the receiving direction, to pass to the other side.
+    pub fn rx_capacity(&self) -> usize { self.receiver.1.buf_len() }
+
+    /// The file descriptor for the shared memory area
+    pub fn memfd(&self) -> &memfd::Memfd { self.sender.memfd() }
+
+    /// The file descriptor written to when this side should wake up
+    pub fn signal(&self) -> &File { &self.doorbell.eventfd }
+
+    /// The file descriptor written to when the other side should wake up
+    pub fn peer_signal(&self) -> &File { &self.peer_signal }
+
+    /// Checks that the other side set up the ringbuffers for the same item types.
+    pub fn check_fingerprint(&self) -> Result<(), Error> {
+        self.sender.check_fingerprint()?;
+        self.receiver.check_fingerprint()
+    }
+}
+
+#[test]
+fn duplex() {
+    let mut a: Duplex<u32, u64> = Duplex::new(1000, 500).unwrap();
+    let mut b: Duplex<u64, u32> = Duplex::open(
+        a.rx_capacity(),
+        a.tx_capacity(),
+        a.memfd().as_file().try_clone().unwrap(),
+        a.peer_signal().try_clone().unwrap(),
+        a.signal().try_clone().unwrap(),
+    )
+    .unwrap();
+    b.check_fingerprint().unwrap();
+    assert!(a.sender().descriptor().is_err());
+    assert!(a.receiver().interrupter().is_err());
+
+    a.sender().send_from(&[1, 2, 3]).unwrap();
+    assert_eq!(b.receiver().recv_into(&mut [0; 4]).unwrap(), 3);
+    b.sender().send_from(&[4]).unwrap();
+    assert_eq!(a.receiver().recv_into(&mut [0; 4]).unwrap(), 1);
+
+    // Both halves of "a" sleep on the same eventfd at the same time, and both need to wake up.
+    let (mut tx, mut rx) = a.split();
+    let fill = vec![0; tx.1.buf_len()];
+    assert_eq!(tx.send_from(&fill).unwrap(), fill.len());
+    let t1 = std::thread::spawn(move || tx.block_until_writable().map(|_| ()));
+    let t2 = std::thread::spawn(move || rx.block_until_readable().map(|_| ()));
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    b.sender().send_from(&[5]).unwrap();
+    let mut buf = vec![0; fill.len()];
+    assert_eq!(b.receiver().recv_into(&mut buf).unwrap(), fill.len());
+    t1.join().unwrap().unwrap();
+    t2.join().unwrap().unwrap();
+}
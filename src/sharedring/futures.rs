@@ -0,0 +1,318 @@
+//! `futures::Stream` for `Receiver` and `futures::Sink` for `Sender`.
+//!
+//! These work with any async runtime: a background thread waits for the eventfds to become
+//! readable using epoll, and wakes up the tasks waiting for them. Ringbuffers set up with
+//! futex wakeups cannot be used here, since there is no file descriptor to wait on.
+
+use super::{poll_readable, wait, Inner, Receiver, Sender, Signal};
+use crate::Error;
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// The epoll thread shared by all ringbuffers.
+struct Reactor {
+    epoll: File,
+    wakers: Mutex<HashMap<u64, Waker>>,
+    /// The OS error that stopped the thread, or zero while it is running.
+    failed: AtomicI32,
+}
+
+fn reactor() -> Result<&'static Reactor, std::io::Error> {
+    static REACTOR: Mutex<Option<&'static Reactor>> = Mutex::new(None);
+    let mut r = REACTOR.lock().unwrap();
+    if let Some(r) = *r {
+        return Ok(r);
+    }
+    let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let epoll = unsafe { File::from_raw_fd(fd) };
+    let reactor: &'static Reactor = Box::leak(Box::new(Reactor { epoll, wakers: Default::default(), failed: AtomicI32::new(0) }));
+    std::thread::Builder::new().name("shmem-ipc reactor".into()).spawn(move || reactor.run())?;
+    *r = Some(reactor);
+    Ok(reactor)
+}
+
+impl Reactor {
+    fn run(&self) {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 16];
+        loop {
+            let n = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, -1) };
+            if n == -1 {
+                let e = std::io::Error::last_os_error();
+                if e.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                // Nobody would be woken up from now on, so wake everyone up to find out.
+                self.failed.store(e.raw_os_error().unwrap_or(libc::EIO), Ordering::SeqCst);
+                self.wakers.lock().unwrap().drain().for_each(|(_, w)| w.wake());
+                return;
+            }
+            let mut wakers = self.wakers.lock().unwrap();
+            for e in &events[..n as usize] {
+                if let Some(w) = wakers.remove(&{ e.u64 }) {
+                    w.wake();
+                }
+            }
+        }
+    }
+
+    /// Returns the error that stopped the thread, if it has stopped.
+    fn check(&self) -> Result<(), std::io::Error> {
+        match self.failed.load(Ordering::SeqCst) {
+            0 => Ok(()),
+            e => Err(std::io::Error::from_raw_os_error(e)),
+        }
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, token: u64) -> Result<(), std::io::Error> {
+        let mut ev = libc::epoll_event { events: (libc::EPOLLIN | libc::EPOLLONESHOT) as u32, u64: token };
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut ev) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// A signal registered with the reactor. Deregistered when dropped.
+#[derive(Default)]
+pub(super) struct Registration(Option<(u64, RawFd)>);
+
+impl Registration {
+    /// Wakes "waker" once, the next time "fd" is readable.
+    fn arm(&mut self, fd: RawFd, waker: &Waker) -> Result<(), std::io::Error> {
+        static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+        let r = reactor()?;
+        let token = match self.0 {
+            Some((token, _)) => {
+                r.wakers.lock().unwrap().insert(token, waker.clone());
+                r.ctl(libc::EPOLL_CTL_MOD, fd, token)?;
+                token
+            }
+            None => {
+                let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+                r.wakers.lock().unwrap().insert(token, waker.clone());
+                r.ctl(libc::EPOLL_CTL_ADD, fd, token)?;
+                self.0 = Some((token, fd));
+                token
+            }
+        };
+        // Checked after inserting the waker: if the thread stopped before that, we see it here.
+        if let Err(e) = r.check() {
+            r.wakers.lock().unwrap().remove(&token);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some((token, fd)) = self.0 {
+            // Something was registered, so the reactor is there already.
+            if let Ok(r) = reactor() {
+                let _ = r.ctl(libc::EPOLL_CTL_DEL, fd, token);
+                r.wakers.lock().unwrap().remove(&token);
+            }
+        }
+    }
+}
+
+/// Items on their way between the ringbuffer and the stream or sink.
+pub(super) struct Batch<T> {
+    items: VecDeque<T>,
+    error: Option<Error>,
+}
+
+// The items are never pinned.
+impl<T> Unpin for Batch<T> {}
+
+impl<T> Default for Batch<T> {
+    fn default() -> Self { Batch { items: VecDeque::new(), error: None } }
+}
+
+/// Returns the non-zero number from "count", or arranges for the task to be woken up when
+/// the signal has been written to. The waiting flag goes through "waiter", like in `Waiter::block`.
+fn poll_count<F, W>(waiter: &mut super::Waiter, signal: &Signal, cx: &mut Context<'_>, count: F, set_waiting: W) -> Poll<Result<usize, Error>>
+where
+    F: Fn() -> Result<usize, Error>,
+    W: Fn(bool),
+{
+    waiter.check_interrupt()?;
+    let s = count()?;
+    if s > 0 {
+        return Poll::Ready(Ok(s));
+    }
+    let f = signal.file()?;
+    // Drain the eventfd before checking again, so that a signal after the check is not lost.
+    waiter.start_waiting(&set_waiting);
+    if poll_readable(f, Duration::ZERO)? {
+        wait(f)?;
+    }
+    let s = count()?;
+    if s > 0 {
+        waiter.stop_waiting(&set_waiting);
+        return Poll::Ready(Ok(s));
+    }
+    waiter.registration.arm(f.as_raw_fd(), cx.waker())?;
+    Poll::Pending
+}
+
+impl Inner {
+    fn poll_writable<T: Copy + zerocopy::AsBytes>(&mut self, ringbuf: &crate::ringbuf::Sender<T>, cx: &mut Context<'_>) -> Poll<Result<usize, Error>> {
+        let mark = ringbuf.write_watermark();
+        let count = || {
+            let s = ringbuf.write_count()?;
+            Ok(if s >= mark { s } else { 0 })
+        };
+        poll_count(&mut self.waiter, &self.full_signal, cx, count, |w| ringbuf.set_waiting(w))
+    }
+
+    fn poll_readable<T: Copy + zerocopy::FromBytes>(&mut self, ringbuf: &crate::ringbuf::Receiver<T>, cx: &mut Context<'_>) -> Poll<Result<usize, Error>> {
+        let mark = ringbuf.read_watermark();
+        let count = || {
+            let s = ringbuf.read_count()?;
+            Ok(if s >= mark { s } else { 0 })
+        };
+        poll_count(&mut self.waiter, &self.empty_signal, cx, count, |w| ringbuf.set_waiting(w))
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
+    /// Returns the error that ended the stream, if any.
+    ///
+    /// Since the stream's items are just T, an error (e g a corrupt buffer, or a ringbuffer set up
+    /// with futex wakeups) ends the stream, and is kept here.
+    pub fn take_stream_error(&mut self) -> Option<Error> { self.2.error.take() }
+
+    fn poll_batch(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let Receiver(inner, ringbuf, ..) = self;
+        futures_core::ready!(inner.poll_readable(ringbuf, cx))?;
+        // Receive everything available in one go, to signal the sending side at most once.
+        let mut items = std::mem::take(&mut self.2.items);
+        items.extend(self.drain()?);
+        self.2.items = items;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(item) = this.2.items.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+            if this.2.error.is_some() {
+                return Poll::Ready(None);
+            }
+            if let Err(e) = futures_core::ready!(this.poll_batch(cx)) {
+                this.2.error = Some(e);
+            }
+        }
+    }
+}
+
+impl<T: Copy + zerocopy::AsBytes> Sender<T> {
+    /// Sends as many of the pending items as there is room for.
+    fn poll_send_batch(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.2.items.is_empty() {
+            let Sender(inner, ringbuf, ..) = self;
+            futures_core::ready!(inner.poll_writable(ringbuf, cx))?;
+            let mut items = std::mem::take(&mut self.2.items);
+            let r = self.send_from(items.make_contiguous());
+            if let Ok(n) = r {
+                items.drain(..n);
+            }
+            self.2.items = items;
+            r?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Copy + zerocopy::AsBytes> Sink<T> for Sender<T> {
+    type Error = Error;
+
+    /// Items are collected, and sent when a ringbuffer's worth of items is pending, or on flush.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        if this.2.items.len() < this.1.buf_len() {
+            return Poll::Ready(Ok(()));
+        }
+        this.poll_send_batch(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Error> {
+        self.get_mut().2.items.push_back(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> { self.get_mut().poll_send_batch(cx) }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> { self.get_mut().poll_send_batch(cx) }
+}
+
+#[test]
+fn stream_sink() {
+    use ::futures::{SinkExt, StreamExt};
+    let r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let e = r.empty_signal().try_clone().unwrap();
+    let f = r.full_signal().try_clone().unwrap();
+    let mut s: Sender<u32> = Sender::open(1000, memfd, e, f).unwrap();
+    let total = s.1.buf_len() as u32 * 3 + 7;
+    let t = std::thread::spawn(move || {
+        ::futures::executor::block_on(async {
+            let mut items = ::futures::stream::iter((0..total).map(Ok));
+            s.send_all(&mut items).await.unwrap();
+        })
+    });
+    let r = ::futures::executor::block_on(async {
+        let mut r = r;
+        let mut expected = 0;
+        while expected < total {
+            assert_eq!(r.next().await, Some(expected));
+            expected += 1;
+        }
+        r
+    });
+    t.join().unwrap();
+    assert_eq!(r.2.items.len(), 0);
+}
+
+#[test]
+fn stream_error() {
+    use ::futures::StreamExt;
+    let mut r: Receiver<u32> = Receiver::new_futex(1000).unwrap();
+    assert_eq!(::futures::executor::block_on(r.next()), None);
+    assert!(r.take_stream_error().is_some());
+}
+
+#[test]
+fn poll_count_waiting_flag() {
+    // Items arriving between the two checks in poll_count.
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let Receiver(inner, ..) = &mut r;
+    let flag = std::cell::Cell::new(true);
+    let checks = std::cell::Cell::new(0);
+    let count = || {
+        checks.set(checks.get() + 1);
+        Ok(checks.get() - 1)
+    };
+    let waker = ::futures::task::noop_waker();
+    let p = poll_count(&mut inner.waiter, &inner.empty_signal, &mut Context::from_waker(&waker), count, |w| flag.set(w));
+    assert!(matches!(p, Poll::Ready(Ok(1))));
+    assert_eq!(inner.waiter.waiting, flag.get());
+}
@@ -0,0 +1,198 @@
+//! Transfers a ringbuffer's capacity and file descriptors over a unix socket.
+//!
+//! The side that sets up the ringbuffer calls `offer` with its `RingDescriptor`, and the other
+//! side calls `accept` and attaches to the ringbuffer through the returned `RingDescriptor`.
+//!
+//! The file descriptors are sent as SCM_RIGHTS ancillary data, together with a small header:
+//! a magic number, the protocol version, flags for the layout and the capacity. The peer is untrusted, so
+//! `accept` checks the header and the number of file descriptors, and any file descriptors
+//! received are closed if the handshake fails.
+
+use super::{Layout, RingDescriptor};
+use crate::Error;
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+const MAGIC: u32 = 0x6752_6853; // "ShRg"
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 16;
+/// The ringbuffer uses futex wakeups, so only the memfd is sent.
+const FLAG_FUTEX: u16 = 1;
+const FLAG_MIRRORED: u16 = 2;
+/// More than we ever send, so that we can tell if the peer sends too many.
+const MAX_FDS: usize = 8;
+
+/// Sends the descriptor of a ringbuffer to the other side.
+pub fn offer(stream: &UnixStream, descriptor: &RingDescriptor) -> Result<(), Error> {
+    let mut header = [0u8; HEADER_LEN];
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    let flags = match descriptor.layout() {
+        Layout::Plain => 0,
+        Layout::Futex => FLAG_FUTEX,
+        Layout::Mirrored => FLAG_MIRRORED,
+    };
+    header[6..8].copy_from_slice(&flags.to_le_bytes());
+    header[8..16].copy_from_slice(&(descriptor.capacity() as u64).to_le_bytes());
+    let mut fds = vec![descriptor.memfd().as_raw_fd()];
+    if let Some((e, f)) = descriptor.signals() {
+        fds.extend([e.as_raw_fd(), f.as_raw_fd()]);
+    }
+    send_fds(stream, &header, &fds)
+}
+
+/// Receives the descriptor of a ringbuffer offered by the other side with `offer`.
+pub fn accept(stream: &UnixStream) -> Result<RingDescriptor, Error> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut fds = recv_fds(stream, &mut header)?;
+    if u32::from_le_bytes(header[0..4].try_into().unwrap()) != MAGIC {
+        Err(Error::Handshake("Not a ringbuffer offer"))?
+    }
+    if u16::from_le_bytes(header[4..6].try_into().unwrap()) != VERSION {
+        Err(Error::Handshake("Unsupported version"))?
+    }
+    let layout = match u16::from_le_bytes(header[6..8].try_into().unwrap()) {
+        0 => Layout::Plain,
+        FLAG_FUTEX => Layout::Futex,
+        FLAG_MIRRORED => Layout::Mirrored,
+        _ => Err(Error::Handshake("Unknown flags"))?,
+    };
+    let capacity = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let capacity = usize::try_from(capacity).map_err(|_| Error::Handshake("Capacity too large"))?;
+    let expected = if layout == Layout::Futex { 1 } else { 3 };
+    if fds.len() != expected {
+        Err(Error::Handshake("Wrong number of file descriptors"))?
+    }
+    let signals = if expected == 3 {
+        let f = fds.pop().unwrap();
+        let e = fds.pop().unwrap();
+        Some((e, f))
+    } else {
+        None
+    };
+    RingDescriptor::new(capacity, layout, fds.pop().unwrap(), signals)
+}
+
+fn send_fds(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> Result<(), Error> {
+    let fds_len = std::mem::size_of_val(fds) as u32;
+    let mut cmsg = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg.len() as _;
+    unsafe {
+        let c = libc::CMSG_FIRSTHDR(&msg);
+        (*c).cmsg_level = libc::SOL_SOCKET;
+        (*c).cmsg_type = libc::SCM_RIGHTS;
+        (*c).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(c) as *mut RawFd, fds.len());
+    }
+    let n = loop {
+        let n = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if n >= 0 {
+            break n as usize;
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            Err(e)?
+        }
+    };
+    // The file descriptors went with the first byte, so the rest is just data.
+    let mut stream = stream;
+    std::io::Write::write_all(&mut stream, &data[n..])?;
+    Ok(())
+}
+
+/// Fills "data", returning the file descriptors received with it.
+fn recv_fds(stream: &UnixStream, data: &mut [u8]) -> Result<Vec<File>, Error> {
+    let mut cmsg = vec![0u8; unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) } as usize];
+    let mut iov = libc::iovec { iov_base: data.as_mut_ptr() as *mut libc::c_void, iov_len: data.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg.len() as _;
+    let n = loop {
+        let n = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if n >= 0 {
+            break n as usize;
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            Err(e)?
+        }
+    };
+    // Take ownership of everything we got first, so that it is closed if we return an error.
+    let mut fds = vec![];
+    let mut c = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !c.is_null() {
+        unsafe {
+            if (*c).cmsg_level == libc::SOL_SOCKET && (*c).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*c).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let p = libc::CMSG_DATA(c) as *const RawFd;
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    fds.push(File::from_raw_fd(std::ptr::read_unaligned(p.add(i))));
+                }
+            }
+            c = libc::CMSG_NXTHDR(&msg, c);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        Err(Error::Handshake("Too many file descriptors"))?
+    }
+    if n == 0 {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+    }
+    let mut stream = stream;
+    std::io::Read::read_exact(&mut stream, &mut data[n..])?;
+    Ok(fds)
+}
+
+#[test]
+fn offer_accept() {
+    use super::{Receiver, Sender};
+    let (a, b) = UnixStream::pair().unwrap();
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    offer(&a, &r.descriptor().unwrap()).unwrap();
+    let mut s: Sender<u32> = Sender::open_descriptor(accept(&b).unwrap()).unwrap();
+    s.send_from(&[1, 2, 3]).unwrap();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 3);
+
+    for mut s in [Sender::<u32>::new_futex(1000).unwrap(), Sender::new_mirrored(1000).unwrap()] {
+        let d = s.descriptor().unwrap();
+        offer(&b, &d).unwrap();
+        let acc = accept(&a).unwrap();
+        assert_eq!((acc.layout(), acc.capacity()), (d.layout(), d.capacity()));
+        let mut r: Receiver<u32> = Receiver::open_descriptor(acc).unwrap();
+        s.send_from(&[4]).unwrap();
+        assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+    }
+}
+
+#[test]
+fn bad_offers() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let memfd = super::Receiver::<u32>::new(1000).unwrap().memfd().as_file().try_clone().unwrap();
+    let mut header = [0u8; HEADER_LEN];
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+
+    // Too few, too many and far too many file descriptors
+    send_fds(&a, &header, &[memfd.as_raw_fd(); 2]).unwrap();
+    assert!(matches!(accept(&b), Err(Error::Handshake(_))));
+    send_fds(&a, &header, &[memfd.as_raw_fd(); 4]).unwrap();
+    assert!(matches!(accept(&b), Err(Error::Handshake(_))));
+    send_fds(&a, &header, &[memfd.as_raw_fd(); MAX_FDS + 1]).unwrap();
+    assert!(matches!(accept(&b), Err(Error::Handshake(_))));
+
+    header[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    send_fds(&a, &header, &[memfd.as_raw_fd(); 3]).unwrap();
+    assert!(matches!(accept(&b), Err(Error::Handshake(_))));
+
+    drop(a);
+    assert!(matches!(accept(&b), Err(Error::Io(_))));
+}
@@ -0,0 +1,135 @@
+//! `mio::event::Source` for `Sender` and `Receiver`.
+//!
+//! The receiver registers its empty signal, and the sender registers its full signal, so an
+//! event means that the ringbuffer might have become readable or writable, respectively.
+//! After an event, call `drain_signal` and then read or write until the ringbuffer is empty or full: the signal is only readable again once
+//! the other side has signalled again.
+
+use super::{poll_readable, wait, Receiver, Sender, Signal};
+use crate::Error;
+use ::mio::event::Source;
+use ::mio::unix::SourceFd;
+use ::mio::{Interest, Registry, Token};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+/// Reads the eventfd, returning false if it had not been signalled.
+///
+/// The eventfd is shared with the other side, so it is left blocking, and polled before reading.
+fn drain(signal: &Signal) -> Result<bool, Error> {
+    let f = signal.file()?;
+    if !poll_readable(f, Duration::ZERO)? {
+        return Ok(false);
+    }
+    wait(f)?;
+    Ok(true)
+}
+
+/// The signal is always registered as readable, whatever the interest in the ringbuffer is.
+fn register(signal: &Signal, registry: &Registry, token: Token, reregister: bool) -> Result<(), std::io::Error> {
+    let f = signal.file()?;
+    let mut fd = SourceFd(&f.as_raw_fd());
+    if reregister {
+        fd.reregister(registry, token, Interest::READABLE)
+    } else {
+        fd.register(registry, token, Interest::READABLE)
+    }
+}
+
+fn deregister(signal: &Signal, registry: &Registry) -> Result<(), std::io::Error> {
+    SourceFd(&signal.file()?.as_raw_fd()).deregister(registry)
+}
+
+impl<T: Copy + zerocopy::AsBytes> Sender<T> {
+    /// Reads the full signal, returning true if the receiving side had signalled it.
+    ///
+    /// Only useful while the sender is registered with mio. Returns `Error::Cancelled` once
+    /// interrupted, see `Interrupter`.
+    pub fn drain_signal(&mut self) -> Result<bool, Error> {
+        self.0.waiter.check_interrupt()?;
+        drain(&self.0.full_signal)
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Receiver<T> {
+    /// Reads the empty signal, returning true if the sending side had signalled it.
+    ///
+    /// Only useful while the receiver is registered with mio. Returns `Error::Cancelled` once
+    /// interrupted, see `Interrupter`.
+    pub fn drain_signal(&mut self) -> Result<bool, Error> {
+        self.0.waiter.check_interrupt()?;
+        drain(&self.0.empty_signal)
+    }
+}
+
+impl<T: Copy + zerocopy::AsBytes> Source for Sender<T> {
+    fn register(&mut self, registry: &Registry, token: Token, _: Interest) -> Result<(), std::io::Error> {
+        register(&self.0.full_signal, registry, token, false)?;
+        // We don't know when the event loop checks the buffer, so the receiver must always signal.
+        let ringbuf = &self.1;
+        self.0.waiter.register(|w| ringbuf.set_waiting(w));
+        Ok(())
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> Result<(), std::io::Error> {
+        register(&self.0.full_signal, registry, token, true)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<(), std::io::Error> {
+        deregister(&self.0.full_signal, registry)?;
+        self.0.waiter.unregister();
+        Ok(())
+    }
+}
+
+impl<T: Copy + zerocopy::FromBytes> Source for Receiver<T> {
+    fn register(&mut self, registry: &Registry, token: Token, _: Interest) -> Result<(), std::io::Error> {
+        register(&self.0.empty_signal, registry, token, false)?;
+        let ringbuf = &self.1;
+        self.0.waiter.register(|w| ringbuf.set_waiting(w));
+        Ok(())
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _: Interest) -> Result<(), std::io::Error> {
+        register(&self.0.empty_signal, registry, token, true)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<(), std::io::Error> {
+        deregister(&self.0.empty_signal, registry)?;
+        self.0.waiter.unregister();
+        Ok(())
+    }
+}
+
+#[test]
+fn mio_poll() {
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let e = r.empty_signal().try_clone().unwrap();
+    let f = r.full_signal().try_clone().unwrap();
+    let mut s: Sender<u32> = Sender::open(1000, memfd, e, f).unwrap();
+    let mut poll = ::mio::Poll::new().unwrap();
+    let mut events = ::mio::Events::with_capacity(4);
+    poll.registry().register(&mut r, Token(1), Interest::READABLE).unwrap();
+    poll.registry().register(&mut s, Token(2), Interest::WRITABLE).unwrap();
+    assert!(!r.drain_signal().unwrap());
+
+    let items = vec![5u32; s.1.buf_len()];
+    assert_eq!(s.send_from(&items).unwrap(), items.len());
+    poll.poll(&mut events, Some(std::time::Duration::from_secs(10))).unwrap();
+    assert_eq!(events.iter().map(|e| e.token()).collect::<Vec<_>>(), &[Token(1)]);
+    assert!(r.drain_signal().unwrap());
+    assert!(!r.drain_signal().unwrap());
+    assert_eq!(r.recv_into(&mut [0; 10]).unwrap(), 10);
+
+    poll.poll(&mut events, Some(std::time::Duration::from_secs(10))).unwrap();
+    assert_eq!(events.iter().map(|e| e.token()).collect::<Vec<_>>(), &[Token(2)]);
+    assert!(s.drain_signal().unwrap());
+    assert_eq!(s.send_from(&items).unwrap(), 10);
+
+    poll.registry().reregister(&mut r, Token(3), Interest::READABLE).unwrap();
+    assert_eq!(r.0.waiter.registered.count.load(std::sync::atomic::Ordering::SeqCst), 1);
+    poll.registry().deregister(&mut r).unwrap();
+    assert_eq!(r.0.waiter.registered.count.load(std::sync::atomic::Ordering::SeqCst), 0);
+    assert_eq!(r.block_until_readable().unwrap().remaining, items.len());
+}
@@ -0,0 +1,212 @@
+//! Waiting for many ringbuffers on one thread.
+//!
+//! This is for e g a server with a ringbuffer per client, which would otherwise need a thread
+//! per client, or an async runtime. The signals of all ringbuffers are added to one epoll
+//! instance, and the ringbuffers themselves stay with the caller.
+
+use super::{poll_readable, wait, Receiver, Registered, Sender, Signal, Waiter};
+use crate::Error;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A signal added to a `Selector`.
+struct Registration {
+    /// Our own duplicate of the signal, so that the file descriptor cannot be closed and
+    /// reused behind our back, even if the ringbuffer is dropped.
+    eventfd: File,
+    /// Shared with the ringbuffer, see `Waiter::registered`.
+    registered: Arc<Registered>,
+}
+
+impl Registration {
+    fn is_orphaned(&self) -> bool { self.registered.dropped.load(Ordering::SeqCst) }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) { self.registered.count.fetch_sub(1, Ordering::SeqCst); }
+}
+
+/// Waits for any number of ringbuffers on one thread, using epoll.
+///
+/// Every ringbuffer is added with a key of your choice, and `select` returns the keys of
+/// the ringbuffers that are ready: receivers that might be readable and senders that might be
+/// writable. The ringbuffers themselves stay with you.
+///
+/// To be fair to all ringbuffers, every ready ringbuffer is returned once per call to `select`,
+/// and you should only do a bounded amount of work with each one (e g, one `recv_into` with
+/// a fixed size buffer). A ringbuffer is only returned again once the other side signals it,
+/// so if you leave items in it, call `set_ready` to get it back in line, behind the others.
+pub struct Selector {
+    epoll: File,
+    fds: HashMap<usize, Registration>,
+    ready: VecDeque<usize>,
+}
+
+impl Selector {
+    /// Creates a selector without any ringbuffers.
+    pub fn new() -> Result<Self, Error> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd == -1 {
+            Err(std::io::Error::last_os_error())?
+        }
+        Ok(Selector { epoll: unsafe { File::from_raw_fd(fd) }, fds: HashMap::new(), ready: VecDeque::new() })
+    }
+
+    fn add<W: Fn(bool)>(&mut self, signal: &Signal, waiter: &Waiter, key: usize, set_waiting: W) -> Result<(), Error> {
+        if self.fds.contains_key(&key) {
+            Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "Key already added to selector"))?
+        }
+        let eventfd = signal.file()?.try_clone()?;
+        let mut ev = libc::epoll_event { events: libc::EPOLLIN as u32, u64: key as u64 };
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, eventfd.as_raw_fd(), &mut ev) } == -1 {
+            Err(std::io::Error::last_os_error())?
+        }
+        // We don't know when the caller checks the buffer, so the other side must always signal.
+        waiter.register(set_waiting);
+        self.fds.insert(key, Registration { eventfd, registered: waiter.registered.clone() });
+        // There might be items already, from before it was added.
+        self.set_ready(key);
+        Ok(())
+    }
+
+    /// Adds a receiver, which is returned from `select` when it might be readable.
+    ///
+    /// Fails if the ringbuffer was set up with futex wakeups, since there is no file descriptor to wait on.
+    pub fn add_receiver<T: Copy + zerocopy::FromBytes>(&mut self, receiver: &Receiver<T>, key: usize) -> Result<(), Error> {
+        self.add(&receiver.0.empty_signal, &receiver.0.waiter, key, |w| receiver.1.set_waiting(w))
+    }
+
+    /// Adds a sender, which is returned from `select` when it might be writable.
+    ///
+    /// Fails if the ringbuffer was set up with futex wakeups, since there is no file descriptor to wait on.
+    pub fn add_sender<T: Copy + zerocopy::AsBytes>(&mut self, sender: &Sender<T>, key: usize) -> Result<(), Error> {
+        self.add(&sender.0.full_signal, &sender.0.waiter, key, |w| sender.1.set_waiting(w))
+    }
+
+    /// Removes the ringbuffer added with this key.
+    ///
+    /// Ringbuffers that are dropped are removed by the next `select`, but their keys can only
+    /// be reused after that.
+    pub fn remove(&mut self, key: usize) -> Result<(), Error> {
+        if let Some(r) = self.fds.remove(&key) {
+            self.ready.retain(|k| *k != key);
+            if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_DEL, r.eventfd.as_raw_fd(), std::ptr::null_mut()) } == -1 {
+                Err(std::io::Error::last_os_error())?
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `select` return the key again, after the keys that are ready already.
+    pub fn set_ready(&mut self, key: usize) {
+        if self.fds.contains_key(&key) && !self.ready.contains(&key) {
+            self.ready.push_back(key);
+        }
+    }
+
+    /// Waits until at least one ringbuffer is ready, or the timeout has passed, and returns
+    /// the keys of the ready ringbuffers.
+    ///
+    /// The keys are returned in the order they got ready. If the timeout passes first,
+    /// nothing is returned.
+    pub fn select(&mut self, timeout: Option<Duration>) -> Result<Vec<usize>, Error> {
+        let orphans: Vec<usize> = self.fds.iter().filter(|(_, r)| r.is_orphaned()).map(|(k, _)| *k).collect();
+        for key in orphans {
+            self.remove(key)?;
+        }
+        let deadline = timeout.map(|d| Instant::now() + d);
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 32];
+        let n = loop {
+            let timeout = match deadline {
+                _ if !self.ready.is_empty() => 0,
+                // Round up, so that we don't return before the timeout has passed.
+                Some(d) => {
+                    let d = d.saturating_duration_since(Instant::now());
+                    std::cmp::min(d.as_nanos().div_ceil(1_000_000), i32::MAX as u128) as i32
+                }
+                None => -1,
+            };
+            let n = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, timeout) };
+            if n != -1 {
+                break n as usize;
+            }
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINTR) {
+                Err(e)?
+            }
+        };
+        for e in &events[..n] {
+            let key = e.u64 as usize;
+            // The eventfd stays readable until read, so read it to only hear about new signals.
+            // The ringbuffer might be waiting on it as well, and have read it already.
+            if let Some(r) = self.fds.get(&key) {
+                if poll_readable(&r.eventfd, Duration::ZERO)? {
+                    wait(&r.eventfd)?;
+                }
+            }
+            self.set_ready(key);
+        }
+        Ok(self.ready.drain(..).collect())
+    }
+}
+
+#[test]
+fn selector() {
+    let mut sel = Selector::new().unwrap();
+    let mut rings = vec![];
+    for key in 0..3 {
+        let r: Receiver<u32> = Receiver::new(100).unwrap();
+        let memfd = r.memfd().as_file().try_clone().unwrap();
+        let s: Sender<u32> = Sender::open(100, memfd, r.empty_signal().try_clone().unwrap(), r.full_signal().try_clone().unwrap()).unwrap();
+        sel.add_receiver(&r, key).unwrap();
+        rings.push((r, s));
+    }
+    assert!(sel.add_receiver(&rings[0].0, 0).is_err());
+    sel.add_sender(&rings[2].1, 10).unwrap();
+    assert_eq!(sel.select(None).unwrap(), &[0, 1, 2, 10]);
+    assert_eq!(sel.select(Some(Duration::from_millis(1))).unwrap(), &[]);
+
+    rings[1].1.send_from(&[1; 50]).unwrap();
+    rings[0].1.send_from(&[1; 5]).unwrap();
+    let mut buf = [0; 10];
+    assert_eq!(sel.select(None).unwrap(), &[1, 0]);
+    for key in [1, 0] {
+        rings[key].0.recv_into(&mut buf).unwrap();
+        if rings[key].0.receiver_mut().read_count().unwrap() > 0 {
+            sel.set_ready(key);
+        }
+    }
+    // The busy ring does not get ahead of the others
+    let items = vec![1; rings[2].1 .1.buf_len()];
+    assert_eq!(rings[2].1.send_from(&items).unwrap(), items.len());
+    assert_eq!(sel.select(None).unwrap(), &[1, 2]);
+    rings[2].0.recv_into(&mut buf).unwrap();
+    assert_eq!(sel.select(None).unwrap(), &[10]);
+
+    sel.remove(1).unwrap();
+    rings[1].1.send_from(&[1; 5]).unwrap();
+    assert_eq!(sel.select(Some(Duration::from_millis(1))).unwrap(), &[]);
+
+    // Waiting on the ringbuffer directly does not stop the sending side from signalling.
+    let (r, s) = &mut rings[0];
+    s.send_from(&[1]).unwrap();
+    r.block_until_readable().unwrap();
+    assert_eq!(r.recv_into(&mut buf).unwrap(), 1);
+    assert_eq!(sel.select(Some(Duration::from_secs(10))).unwrap(), &[0]);
+    s.send_from(&[2]).unwrap();
+    assert_eq!(sel.select(Some(Duration::from_secs(10))).unwrap(), &[0]);
+
+    // Dropped ringbuffers are removed, even if they were added to another selector as well.
+    let mut sel2 = Selector::new().unwrap();
+    sel2.add_receiver(&rings[0].0, 0).unwrap();
+    assert_eq!(sel2.select(None).unwrap(), &[0]);
+    drop(rings.remove(0));
+    sel.set_ready(0);
+    sel2.set_ready(0);
+    assert_eq!(sel.select(Some(Duration::from_millis(1))).unwrap(), &[]);
+    assert_eq!(sel2.select(Some(Duration::from_millis(1))).unwrap(), &[]);
+}
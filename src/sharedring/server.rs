@@ -0,0 +1,353 @@
+//! Serving ringbuffers to clients connecting to a unix socket.
+//!
+//! For every client, the `Listener` sets up two ringbuffers, one in each direction, and sends
+//! them to the client using the `handshake` module: first the one the client sends on, then
+//! the one the client receives on. The client attaches to them with `connect`.
+//!
+//! The socket stays open for as long as the connection lives, so each side can tell when the
+//! other side is gone. On the server side, the connection's ringbuffers are then interrupted,
+//! see `Interrupter`: waiting for them returns `Error::Cancelled`, whether through
+//! `block_until_readable` and `block_until_writable` or through the tokio, futures and mio
+//! integrations, so that the connection can be torn down.
+
+use super::{handshake, Interrupter, Receiver, Sender};
+use crate::Error;
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The limits and ringbuffer setup for every connection to a `Listener`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Capacity, in items, of the ringbuffer from the client to the server.
+    pub rx_capacity: usize,
+    /// Capacity, in items, of the ringbuffer from the server to the client.
+    pub tx_capacity: usize,
+    /// Clients connecting while this many connections are open are disconnected right away,
+    /// and `Listener::accept` returns `Error::Handshake` for each of them.
+    pub max_connections: usize,
+    /// Sets up the ringbuffers with `new_mirrored` instead of `new`.
+    pub mirrored: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self { Config { rx_capacity: 65536, tx_capacity: 65536, max_connections: 64, mirrored: false } }
+}
+
+/// Watches the sockets of all connections of a listener, cancelling the waits of the
+/// connections that hang up.
+struct Watcher {
+    epoll: File,
+    /// Wakes up the thread, to exit once the listener and all connections are gone.
+    wakeup: File,
+    stopped: AtomicBool,
+    /// The OS error that stopped the thread, or zero while it is running.
+    failed: AtomicI32,
+    conns: Mutex<HashMap<u64, (RawFd, Interrupter, Interrupter)>>,
+}
+
+const WAKEUP_TOKEN: u64 = u64::MAX;
+
+impl Watcher {
+    fn start() -> Result<Arc<Self>, Error> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd == -1 {
+            Err(std::io::Error::last_os_error())?
+        }
+        let w = Arc::new(Watcher {
+            epoll: unsafe { File::from_raw_fd(fd) },
+            wakeup: super::eventfd()?,
+            stopped: AtomicBool::new(false),
+            failed: AtomicI32::new(0),
+            conns: Default::default(),
+        });
+        w.ctl(libc::EPOLL_CTL_ADD, w.wakeup.as_raw_fd(), libc::EPOLLIN, WAKEUP_TOKEN)?;
+        let w2 = w.clone();
+        std::thread::Builder::new().name("shmem-ipc listener".into()).spawn(move || w2.run())?;
+        Ok(w)
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: i32, token: u64) -> Result<(), std::io::Error> {
+        let mut ev = libc::epoll_event { events: events as u32, u64: token };
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut ev) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn run(&self) {
+        if let Err(e) = self.watch() {
+            // Nobody would notice the connections hanging up from now on, so cancel them all.
+            self.failed.store(e.raw_os_error().unwrap_or(libc::EIO), Ordering::SeqCst);
+            for (_, (_, i1, i2)) in self.conns.lock().unwrap().drain() {
+                let _ = i1.interrupt();
+                let _ = i2.interrupt();
+            }
+        }
+    }
+
+    fn watch(&self) -> Result<(), std::io::Error> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 16];
+        loop {
+            let n = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, -1) };
+            if n == -1 {
+                let e = std::io::Error::last_os_error();
+                if e.raw_os_error() != Some(libc::EINTR) {
+                    return Err(e);
+                }
+                continue;
+            }
+            let mut conns = self.conns.lock().unwrap();
+            for e in &events[..n as usize] {
+                if e.u64 == WAKEUP_TOKEN {
+                    super::wait(&self.wakeup)?;
+                } else if let Some((fd, i1, i2)) = conns.remove(&{ e.u64 }) {
+                    let _ = self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0);
+                    let _ = i1.interrupt();
+                    let _ = i2.interrupt();
+                }
+            }
+            if self.stopped.load(Ordering::SeqCst) && conns.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn add(&self, token: u64, stream: &UnixStream, interrupters: (Interrupter, Interrupter)) -> Result<(), Error> {
+        let fd = stream.as_raw_fd();
+        self.conns.lock().unwrap().insert(token, (fd, interrupters.0, interrupters.1));
+        if let Err(e) = self.ctl(libc::EPOLL_CTL_ADD, fd, libc::EPOLLRDHUP, token) {
+            self.conns.lock().unwrap().remove(&token);
+            Err(e)?
+        }
+        // Checked after inserting: if the thread stopped before that, we see it here.
+        match self.failed.load(Ordering::SeqCst) {
+            0 => Ok(()),
+            e => {
+                self.remove(token);
+                Err(std::io::Error::from_raw_os_error(e))?
+            }
+        }
+    }
+
+    fn remove(&self, token: u64) {
+        let mut conns = self.conns.lock().unwrap();
+        if let Some((fd, _, _)) = conns.remove(&token) {
+            let _ = self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0);
+        }
+        if self.stopped.load(Ordering::SeqCst) && conns.is_empty() {
+            let _ = super::signal(&self.wakeup);
+        }
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = super::signal(&self.wakeup);
+    }
+}
+
+/// Deregisters the connection from the listener.
+struct Registration {
+    watcher: Arc<Watcher>,
+    token: u64,
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.watcher.remove(self.token);
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Listens on a unix socket, setting up ringbuffers for every client that connects.
+///
+/// The socket file is removed when the listener is dropped.
+pub struct Listener<Rx, Tx> {
+    listener: UnixListener,
+    path: PathBuf,
+    config: Config,
+    watcher: Arc<Watcher>,
+    count: Arc<AtomicUsize>,
+    next_token: u64,
+    _types: std::marker::PhantomData<fn(Rx, Tx)>,
+}
+
+impl<Rx: Copy + zerocopy::FromBytes, Tx: Copy + zerocopy::AsBytes> Listener<Rx, Tx> {
+    /// Binds to the socket path, which must not exist.
+    pub fn bind<P: AsRef<Path>>(path: P, config: Config) -> Result<Self, Error> {
+        let listener = UnixListener::bind(&path)?;
+        let watcher = Watcher::start().map_err(|e| {
+            let _ = std::fs::remove_file(&path);
+            e
+        })?;
+        Ok(Listener {
+            listener,
+            path: path.as_ref().to_owned(),
+            config,
+            watcher,
+            count: Default::default(),
+            next_token: 0,
+            _types: Default::default(),
+        })
+    }
+
+    /// Waits for the next client, and sets up its ringbuffers.
+    ///
+    /// An error setting up one client (e g, because it disconnected right away) does not
+    /// affect other clients, so you can continue accepting after an error. This includes
+    /// `Error::Handshake` for clients over `Config::max_connections`, which are disconnected.
+    pub fn accept(&mut self) -> Result<Connection<Rx, Tx>, Error> {
+        let (stream, _) = self.listener.accept()?;
+        if self.count.load(Ordering::SeqCst) >= self.config.max_connections {
+            Err(Error::Handshake("Too many connections"))?
+        }
+        self.setup(stream)
+    }
+
+    fn setup(&mut self, stream: UnixStream) -> Result<Connection<Rx, Tx>, Error> {
+        let (mut receiver, mut sender) = if self.config.mirrored {
+            (Receiver::new_mirrored(self.config.rx_capacity)?, Sender::new_mirrored(self.config.tx_capacity)?)
+        } else {
+            (Receiver::new(self.config.rx_capacity)?, Sender::new(self.config.tx_capacity)?)
+        };
+        handshake::offer(&stream, &receiver.descriptor()?)?;
+        handshake::offer(&stream, &sender.descriptor()?)?;
+        let token = self.next_token;
+        self.next_token += 1;
+        self.watcher.add(token, &stream, (receiver.interrupter()?, sender.interrupter()?))?;
+        self.count.fetch_add(1, Ordering::SeqCst);
+        let registration = Registration { watcher: self.watcher.clone(), token, count: self.count.clone() };
+        Ok(Connection { _registration: Some(registration), receiver, sender, stream })
+    }
+
+    /// The number of connections currently open.
+    pub fn connections(&self) -> usize { self.count.load(Ordering::SeqCst) }
+
+    /// The configuration passed to `bind`.
+    pub fn config(&self) -> &Config { &self.config }
+
+    /// The listening socket, e g for setting it non-blocking.
+    pub fn socket(&self) -> &UnixListener { &self.listener }
+}
+
+impl<Rx, Tx> Drop for Listener<Rx, Tx> {
+    fn drop(&mut self) {
+        self.watcher.stop();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A client connected to a `Listener`, or the client side of such a connection.
+///
+/// Dropping the connection closes the socket, which tells the other side that we are gone.
+pub struct Connection<Rx, Tx> {
+    // Dropped before the socket is closed.
+    _registration: Option<Registration>,
+    receiver: Receiver<Rx>,
+    sender: Sender<Tx>,
+    stream: UnixStream,
+}
+
+impl<Rx: Copy + zerocopy::FromBytes, Tx: Copy + zerocopy::AsBytes> Connection<Rx, Tx> {
+    /// The ringbuffer from the other side to us.
+    pub fn receiver(&mut self) -> &mut Receiver<Rx> { &mut self.receiver }
+
+    /// The ringbuffer from us to the other side.
+    pub fn sender(&mut self) -> &mut Sender<Tx> { &mut self.sender }
+
+    /// Both ringbuffers, e g to use them from different threads.
+    pub fn split(&mut self) -> (&mut Receiver<Rx>, &mut Sender<Tx>) { (&mut self.receiver, &mut self.sender) }
+
+    /// The socket, e g for checking the peer's credentials.
+    pub fn socket(&self) -> &UnixStream { &self.stream }
+
+    /// Checks that the other side set up the ringbuffers for the same item types.
+    ///
+    /// This is not done by `connect`, since the fingerprint depends on the path of the item types,
+    /// see `Sender::check_fingerprint`.
+    pub fn check_fingerprint(&self) -> Result<(), Error> {
+        self.sender.check_fingerprint()?;
+        self.receiver.check_fingerprint()
+    }
+
+    /// Returns false if the other side has hung up.
+    pub fn is_connected(&self) -> Result<bool, Error> {
+        let mut fds = [libc::pollfd { fd: self.stream.as_raw_fd(), events: libc::POLLRDHUP, revents: 0 }];
+        super::poll(&mut fds, Some(std::time::Duration::ZERO))?;
+        Ok(fds[0].revents == 0)
+    }
+}
+
+/// Connects to a `Listener`, and attaches to the ringbuffers it sets up.
+///
+/// "Rx" and "Tx" are the item types from the client's point of view, so they are the other
+/// way around compared to the listener. Their size and alignment are verified; call
+/// `Connection::check_fingerprint` to verify the types themselves.
+pub fn connect<Rx: Copy + zerocopy::FromBytes, Tx: Copy + zerocopy::AsBytes, P: AsRef<Path>>(path: P) -> Result<Connection<Rx, Tx>, Error> {
+    let stream = UnixStream::connect(path)?;
+    let sender = Sender::open_descriptor(handshake::accept(&stream)?)?;
+    let receiver = Receiver::open_descriptor(handshake::accept(&stream)?)?;
+    Ok(Connection { _registration: None, receiver, sender, stream })
+}
+
+#[test]
+fn listener() {
+    let path = std::env::temp_dir().join(format!("shmem-ipc-test-{}.sock", std::process::id()));
+    let config = Config { rx_capacity: 1000, tx_capacity: 100, max_connections: 1, mirrored: false };
+    let mut l: Listener<u32, u8> = Listener::bind(&path, config).unwrap();
+    // The path exists already
+    assert!(Listener::<u32, u8>::bind(&path, config).is_err());
+    let path2 = path.clone();
+    let t = std::thread::spawn(move || {
+        let mut c: Connection<u8, u32> = connect(&path2).unwrap();
+        c.check_fingerprint().unwrap();
+        c.sender().send_from(&[1, 2, 3]).unwrap();
+        c.receiver().block_until_readable().unwrap();
+        assert_eq!(c.receiver().recv_into(&mut [0; 4]).unwrap(), 1);
+        assert!(c.is_connected().unwrap());
+    });
+    let mut c = l.accept().unwrap();
+    assert_eq!(l.connections(), 1);
+    c.receiver().block_until_readable().unwrap();
+    assert_eq!(c.receiver().recv_into(&mut [0; 4]).unwrap(), 3);
+    c.sender().send_from(&[4]).unwrap();
+    t.join().unwrap();
+
+    // The client is gone
+    assert!(matches!(c.receiver().block_until_readable(), Err(Error::Cancelled)));
+    assert!(!c.is_connected().unwrap());
+
+    // Only one connection at a time, so the next client is turned away until "c" is dropped
+    let (tx, rx) = std::sync::mpsc::channel();
+    let t1 = std::thread::spawn(move || {
+        rx.recv().unwrap();
+        drop(c);
+    });
+    let t2 = std::thread::spawn(move || {
+        assert!(matches!(connect::<u8, u32, _>(&path), Err(Error::Io(_))));
+        tx.send(()).unwrap();
+        loop {
+            match connect::<u16, u32, _>(&path) {
+                Err(Error::Io(_)) => std::thread::sleep(std::time::Duration::from_millis(1)),
+                // Wrong item size
+                Err(_) => break,
+                Ok(_) => panic!(),
+            }
+        }
+    });
+    assert!(matches!(l.accept(), Err(Error::Handshake(_))));
+    let _c = loop {
+        match l.accept() {
+            Err(Error::Handshake(_)) => continue,
+            r => break r.unwrap(),
+        }
+    };
+    t1.join().unwrap();
+    t2.join().unwrap();
+    assert_eq!(l.connections(), 1);
+}
@@ -0,0 +1,233 @@
+//! Async wrappers around `Sender` and `Receiver` for the tokio runtime.
+//!
+//! The signal we wait on is registered with tokio's reactor, so instead of blocking the thread,
+//! `writable` and `readable` wait for the other side asynchronously.
+//! Ringbuffers set up with futex wakeups cannot be used here, since there is no file descriptor
+//! to wait on.
+
+use super::{poll_readable, Receiver, Sender, Signal, Waiter};
+use crate::ringbuf::Status;
+use crate::Error;
+use ::tokio::io::unix::AsyncFd;
+use std::fs::File;
+use std::io::Read;
+use std::time::Duration;
+
+/// The duplicate shares the open file description with the other side, so it stays blocking:
+/// making it non-blocking would make every other duplicate non-blocking too.
+fn async_signal(signal: &Signal) -> Result<AsyncFd<File>, Error> { Ok(AsyncFd::new(signal.file()?.try_clone()?)?) }
+
+/// Waits until "count" returns a non-zero number, draining the eventfd every time it wakes us up.
+///
+/// The eventfd only tells us that something might have happened, so we always check again.
+/// The waiting flag goes through "waiter", which `block_until_readable` and
+/// `block_until_writable` rely on after `into_inner`.
+async fn wait<F, W>(fd: &AsyncFd<File>, waiter: &mut Waiter, mut count: F, set_waiting: W) -> Result<usize, Error>
+where
+    F: FnMut() -> Result<usize, Error>,
+    W: Fn(bool),
+{
+    loop {
+        waiter.check_interrupt()?;
+        waiter.start_waiting(&set_waiting);
+        let s = count()?;
+        if s > 0 {
+            waiter.stop_waiting(&set_waiting);
+            return Ok(s);
+        }
+        let mut guard = fd.readable().await?;
+        // Reading an eventfd resets it, so there is nothing more to read after one successful read.
+        // If the other side writes to it in the meantime, tokio gets a new readiness event and
+        // clear_ready does not clear that.
+        let r = guard.try_io(|fd| {
+            // The eventfd is blocking, so only read it if there is something to read.
+            if !poll_readable(fd.get_ref(), Duration::ZERO)? {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            fd.get_ref().read(&mut [0u8; 8])
+        });
+        if let Ok(r) = r {
+            r?;
+            guard.clear_ready();
+        }
+    }
+}
+
+/// Async version of `Sender`.
+pub struct AsyncSender<T> {
+    sender: Sender<T>,
+    full_signal: AsyncFd<File>,
+}
+
+impl<T: Copy + zerocopy::AsBytes> AsyncSender<T> {
+    /// Wraps the sender, registering its full signal with the current tokio runtime.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn new(sender: Sender<T>) -> Result<Self, Error> {
+        let full_signal = async_signal(&sender.0.full_signal)?;
+        Ok(AsyncSender { sender, full_signal })
+    }
+
+    /// Waits until the channel is writable, i e, until at least the write watermark number of
+    /// items can be written.
+    pub async fn writable(&mut self) -> Result<Status, Error> {
+        let ringbuf = &self.sender.1;
+        let mark = ringbuf.write_watermark();
+        let count = || {
+            let s = ringbuf.write_count()?;
+            Ok(if s >= mark { s } else { 0 })
+        };
+        let s = wait(&self.full_signal, &mut self.sender.0.waiter, count, |w| ringbuf.set_waiting(w)).await?;
+        Ok(Status { remaining: s, signal: false })
+    }
+
+    /// Waits until the channel is writable, then sends items like `Sender::send_raw`.
+    pub async fn send_raw<F: FnOnce(*mut T, usize) -> usize>(&mut self, f: F) -> Result<Status, Error> {
+        self.writable().await?;
+        self.sender.send_raw(f)
+    }
+
+    /// Waits until the channel is writable, then copies as many items from the slice as there
+    /// is room for, like `Sender::send_from`.
+    pub async fn send_from(&mut self, items: &[T]) -> Result<usize, Error> {
+        self.writable().await?;
+        self.sender.send_from(items)
+    }
+
+    /// The wrapped sender.
+    pub fn get_ref(&self) -> &Sender<T> { &self.sender }
+
+    /// The wrapped sender.
+    pub fn get_mut(&mut self) -> &mut Sender<T> { &mut self.sender }
+
+    /// Unwraps the sender.
+    pub fn into_inner(self) -> Sender<T> { self.sender }
+}
+
+/// Async version of `Receiver`.
+pub struct AsyncReceiver<T> {
+    receiver: Receiver<T>,
+    empty_signal: AsyncFd<File>,
+}
+
+impl<T: Copy + zerocopy::FromBytes> AsyncReceiver<T> {
+    /// Wraps the receiver, registering its empty signal with the current tokio runtime.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn new(receiver: Receiver<T>) -> Result<Self, Error> {
+        let empty_signal = async_signal(&receiver.0.empty_signal)?;
+        Ok(AsyncReceiver { receiver, empty_signal })
+    }
+
+    /// Waits until the channel is readable, i e, until at least the read watermark number of
+    /// items can be read.
+    pub async fn readable(&mut self) -> Result<Status, Error> {
+        let ringbuf = &self.receiver.1;
+        let mark = ringbuf.read_watermark();
+        let count = || {
+            let s = ringbuf.read_count()?;
+            Ok(if s >= mark { s } else { 0 })
+        };
+        let s = wait(&self.empty_signal, &mut self.receiver.0.waiter, count, |w| ringbuf.set_waiting(w)).await?;
+        Ok(Status { remaining: s, signal: false })
+    }
+
+    /// Waits until the channel is readable, then receives items like `Receiver::receive_raw`.
+    pub async fn receive_raw<F: FnOnce(*const T, usize) -> usize>(&mut self, f: F) -> Result<Status, Error> {
+        self.readable().await?;
+        self.receiver.receive_raw(f)
+    }
+
+    /// Waits until the channel is readable, then copies as many items as are available,
+    /// and fit, into the slice, like `Receiver::recv_into`.
+    pub async fn recv_into(&mut self, items: &mut [T]) -> Result<usize, Error> {
+        self.readable().await?;
+        self.receiver.recv_into(items)
+    }
+
+    /// The wrapped receiver.
+    pub fn get_ref(&self) -> &Receiver<T> { &self.receiver }
+
+    /// The wrapped receiver.
+    pub fn get_mut(&mut self) -> &mut Receiver<T> { &mut self.receiver }
+
+    /// Unwraps the receiver.
+    pub fn into_inner(self) -> Receiver<T> { self.receiver }
+}
+
+#[tokio::test]
+async fn send_receive() {
+    let r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let e = r.empty_signal().try_clone().unwrap();
+    let f = r.full_signal().try_clone().unwrap();
+    let s: Sender<u32> = Sender::open(1000, memfd, e, f).unwrap();
+    let total = s.1.buf_len() as u32 * 3;
+    let mut r = AsyncReceiver::new(r).unwrap();
+    let mut s = AsyncSender::new(s).unwrap();
+    // The other side's duplicate of the eventfd must not turn non-blocking.
+    let fd = std::os::unix::io::AsRawFd::as_raw_fd(s.get_ref().empty_signal());
+    assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_NONBLOCK, 0);
+    let t = ::tokio::spawn(async move {
+        let mut i = 0;
+        while i < total {
+            let items: Vec<u32> = (i..std::cmp::min(i + 100, total)).collect();
+            i += s.send_from(&items).await.unwrap() as u32;
+        }
+    });
+    let mut expected = 0;
+    let mut out = [0u32; 70];
+    while expected < total {
+        let n = r.recv_into(&mut out).await.unwrap();
+        assert!(n > 0);
+        for x in &out[..n] {
+            assert_eq!(*x, expected);
+            expected += 1;
+        }
+        ::tokio::time::sleep(std::time::Duration::from_micros(50)).await;
+    }
+    t.await.unwrap();
+    let mut r = r.into_inner();
+    assert!(matches!(r.block_until_readable_timeout(std::time::Duration::from_millis(1)), Err(Error::Timeout)));
+}
+
+#[tokio::test]
+async fn into_inner_then_block() {
+    let r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let memfd = r.memfd().as_file().try_clone().unwrap();
+    let e = r.empty_signal().try_clone().unwrap();
+    let f = r.full_signal().try_clone().unwrap();
+    let mut s: Sender<u32> = Sender::open(1000, memfd, e, f).unwrap();
+    let mut r = AsyncReceiver::new(r).unwrap();
+    s.send_from(&[1]).unwrap();
+    assert_eq!(r.readable().await.unwrap().remaining, 1);
+
+    // The sending side must know that we are waiting again.
+    let mut r = r.into_inner();
+    assert_eq!(r.recv_into(&mut [0; 4]).unwrap(), 1);
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        s.send_from(&[2]).unwrap();
+    });
+    assert_eq!(r.block_until_readable_timeout(std::time::Duration::from_secs(5)).unwrap().remaining, 1);
+    t.join().unwrap();
+}
+
+#[tokio::test]
+async fn interrupted() {
+    let mut r: Receiver<u32> = Receiver::new(1000).unwrap();
+    let i = r.interrupter().unwrap();
+    let mut r = AsyncReceiver::new(r).unwrap();
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        i.interrupt().unwrap();
+    });
+    assert!(matches!(r.readable().await, Err(Error::Cancelled)));
+    t.join().unwrap();
+}
+
+#[tokio::test]
+async fn futex_not_supported() {
+    let r: Receiver<u32> = Receiver::new_futex(1000).unwrap();
+    assert!(AsyncReceiver::new(r).is_err());
+}